@@ -1,9 +1,16 @@
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use bmos_std::io::IOChannel;
 use bmos_std::kdebug;
+use bmos_std::mem;
+use bmos_std::mem::{Buffer, MemoryError, MemoryKind, MemoryMapEntry};
 use bmos_std::syscall;
+use bmos_std::thread;
+use bmos_std::thread::{ThreadClock, ThreadInfo, ThreadState};
+use core::time::Duration;
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
 
@@ -32,12 +39,261 @@ impl ShellBuiltin for Something {
     }
 }
 
+pub struct MemInfo;
+
+impl ShellBuiltin for MemInfo {
+    fn execute(&self, arguments: Vec<&str>) {
+        let info = mem::info();
+        let lines = [
+            format!(
+                "Physical memory: {} of {} KiB used",
+                info.physical_used / 1024,
+                info.physical_total / 1024
+            ),
+            format!(
+                "Kernel heap:     {} of {} KiB used, grows up to {} KiB",
+                info.heap_used / 1024,
+                info.heap_size / 1024,
+                info.heap_limit / 1024
+            ),
+            format!(
+                "Slabs:           {} of {} KiB used",
+                info.slab_used / 1024,
+                info.slab_size / 1024
+            ),
+            format!(
+                "Kernel pages:    {} KiB of {} MiB address space used",
+                info.kernel_pages_used / 1024,
+                info.kernel_pages_size / (1024 * 1024)
+            ),
+            format!(
+                "Kernel stacks:   {} KiB of {} MiB address space used",
+                info.kernel_stacks_used / 1024,
+                info.kernel_stacks_size / (1024 * 1024)
+            ),
+        ];
+
+        syscall::print(IOChannel::Stdout, lines.join("\n").as_str());
+    }
+}
+
+pub struct MemMap;
+
+impl ShellBuiltin for MemMap {
+    fn execute(&self, arguments: Vec<&str>) {
+        let mut capacity = 32;
+        let (entries, count) = loop {
+            let mut entries = match Buffer::<MemoryMapEntry>::new(capacity) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    let message = format!("memmap: {:?}", error);
+                    syscall::print(IOChannel::Stdout, message.as_str());
+                    return;
+                }
+            };
+            let count = mem::memory_map(&mut entries);
+            if count <= entries.len() {
+                break (entries, count);
+            }
+            capacity = count;
+        };
+
+        let lines = entries[..count]
+            .iter()
+            .map(|entry| {
+                let kind = match MemoryKind::from_u32(entry.kind) {
+                    Some(MemoryKind::Usable) => String::from("usable"),
+                    Some(MemoryKind::Bootloader) => String::from("bootloader"),
+                    Some(MemoryKind::Uefi) => format!("UEFI type {}", entry.firmware_kind),
+                    Some(MemoryKind::Bios) => format!("BIOS type {}", entry.firmware_kind),
+                    Some(MemoryKind::Unknown) | None => String::from("unknown"),
+                };
+
+                format!(
+                    "{:#014x} - {:#014x} {:>10} KiB  {}",
+                    entry.start,
+                    entry.end,
+                    (entry.end - entry.start) / 1024,
+                    kind
+                )
+            })
+            .collect::<Vec<_>>();
+
+        syscall::print(IOChannel::Stdout, lines.join("\n").as_str());
+    }
+}
+
+pub struct Sleep;
+
+impl ShellBuiltin for Sleep {
+    fn execute(&self, arguments: Vec<&str>) {
+        let seconds = match arguments.as_slice() {
+            [seconds] => seconds.parse::<f64>().ok(),
+            _ => None,
+        };
+
+        // `Duration` panics on anything that doesn't fit its whole seconds.
+        match seconds {
+            Some(seconds) if seconds >= 0.0 && seconds < u64::MAX as f64 => {
+                thread::sleep(Duration::from_secs_f64(seconds))
+            }
+            _ => syscall::print(IOChannel::Stdout, "Usage: sleep <seconds>"),
+        }
+    }
+}
+
+pub struct Ps;
+
+impl ShellBuiltin for Ps {
+    fn execute(&self, arguments: Vec<&str>) {
+        let (threads, clock) = match thread_snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                syscall::print(IOChannel::Stdout, format!("ps: {:?}", error).as_str());
+                return;
+            }
+        };
+
+        // Each thread's share of the time since it was created.
+        let rows = threads
+            .iter()
+            .map(|info| {
+                let lifetime = core::cmp::max(clock.ticks.saturating_sub(info.created_at), 1);
+                (info, info.ticks as f64 * 100.0 / lifetime as f64)
+            })
+            .collect::<Vec<_>>();
+
+        syscall::print(IOChannel::Stdout, thread_table(&rows, &clock).as_str());
+    }
+}
+
+/// Refreshes `top` this many times unless told otherwise. The shell can't take input while a
+/// command runs, so it doesn't run until it is interrupted.
+const TOP_REFRESHES: u32 = 5;
+
+pub struct Top;
+
+impl ShellBuiltin for Top {
+    fn execute(&self, arguments: Vec<&str>) {
+        let refreshes = match arguments.as_slice() {
+            [] => Some(TOP_REFRESHES),
+            [refreshes] => refreshes.parse::<u32>().ok(),
+            _ => None,
+        };
+        let refreshes = match refreshes {
+            Some(refreshes) => refreshes,
+            None => {
+                syscall::print(IOChannel::Stdout, "Usage: top [refreshes]");
+                return;
+            }
+        };
+
+        let (mut previous, mut previous_clock) = match thread_snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                syscall::print(IOChannel::Stdout, format!("top: {:?}", error).as_str());
+                return;
+            }
+        };
+        for refresh in 0..refreshes {
+            thread::sleep(Duration::from_secs(1));
+            let (threads, clock) = match thread_snapshot() {
+                Ok(snapshot) => snapshot,
+                Err(error) => {
+                    syscall::print(IOChannel::Stdout, format!("top: {:?}", error).as_str());
+                    return;
+                }
+            };
+
+            // Each thread's share of the time since the last refresh.
+            let elapsed = core::cmp::max(clock.ticks.saturating_sub(previous_clock.ticks), 1);
+            let earlier = previous
+                .iter()
+                .map(|info| (info.id, info.ticks))
+                .collect::<HashMap<_, _>>();
+            let mut rows = threads
+                .iter()
+                .map(|info| {
+                    let before = earlier.get(&info.id).copied().unwrap_or(0);
+                    let ran = info.ticks.saturating_sub(before);
+                    (info, ran as f64 * 100.0 / elapsed as f64)
+                })
+                .collect::<Vec<_>>();
+            rows.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+            let mut output = format!(
+                "{} threads, up {} s\n",
+                threads.len(),
+                clock.ticks / clock.ticks_per_second
+            );
+            if refresh > 0 {
+                output.insert_str(0, "\n\n");
+            }
+            output.push_str(thread_table(&rows, &clock).as_str());
+            syscall::print(IOChannel::Stdout, output.as_str());
+
+            previous = threads;
+            previous_clock = clock;
+        }
+    }
+}
+
+/// Every thread the kernel knows about, and the timer when the snapshot was taken.
+fn thread_snapshot() -> Result<(Vec<ThreadInfo>, ThreadClock), MemoryError> {
+    let mut capacity = 32;
+    loop {
+        let mut threads = Buffer::<ThreadInfo>::new(capacity)?;
+        let (count, clock) = thread::threads(&mut threads);
+        if count <= threads.len() {
+            return Ok((threads[..count].to_vec(), clock));
+        }
+
+        // Leave some room for threads that start before the next try.
+        capacity = count + 8;
+    }
+}
+
+/// Lists the threads with their share of a CPU in percent.
+fn thread_table(rows: &[(&ThreadInfo, f64)], clock: &ThreadClock) -> String {
+    let mut lines = vec![format!(
+        "{:>5}  {:<20} {:<8} {:>3} {:>6} {:>9} {:>9}",
+        "ID", "NAME", "STATE", "CPU", "%CPU", "TIME", "SWITCHES"
+    )];
+    lines.extend(rows.iter().map(|(info, share)| {
+        let state = match ThreadState::from_u32(info.state) {
+            Some(ThreadState::Ready) => "ready",
+            Some(ThreadState::Running) => "running",
+            Some(ThreadState::Blocked) => "blocked",
+            Some(ThreadState::Dead) => "dead",
+            None => "unknown",
+        };
+
+        format!(
+            "{:>5}  {:<20} {:<8} {:>3} {:>6.1} {:>8.2}s {:>9}",
+            info.id,
+            info.name(),
+            state,
+            info.cpu,
+            share,
+            clock.seconds(info.ticks),
+            info.context_switches
+        )
+    }));
+
+    lines.join("\n")
+}
+
 lazy_static! {
     pub static ref BUILTINS: HashMap<String, Box<(dyn ShellBuiltin + Send + Sync + 'static)>> = {
         let mut builtins =
             HashMap::<_, Box<(dyn ShellBuiltin + Send + Sync + 'static)>>::with_capacity(1);
         builtins.insert(String::from("echo"), Box::new(Echo));
         builtins.insert(String::from("something"), Box::new(Something));
+        builtins.insert(String::from("meminfo"), Box::new(MemInfo));
+        builtins.insert(String::from("memmap"), Box::new(MemMap));
+        builtins.insert(String::from("sleep"), Box::new(Sleep));
+        builtins.insert(String::from("ps"), Box::new(Ps));
+        builtins.insert(String::from("top"), Box::new(Top));
 
         builtins
     };
@@ -1,4 +1,8 @@
 #![no_std]
 #![feature(asm)]
+extern crate alloc;
+
 pub mod io;
+pub mod mem;
 pub mod syscall;
+pub mod thread;
@@ -0,0 +1,294 @@
+use crate::syscall::{
+    syscall4, SYSCALL_MEM_INFO, SYSCALL_MEM_MAP, SYSCALL_MEM_PROTECT, SYSCALL_MEM_REGIONS,
+    SYSCALL_MEM_RESERVE, SYSCALL_MEM_UNMAP,
+};
+use alloc::boxed::Box;
+use core::ops::{BitOr, Deref, DerefMut};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Access rights of a memory region. They are applied to whole pages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Protection(u64);
+
+impl Protection {
+    /// Any access faults.
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1);
+    /// Pages can't be write-only, so this implies `READ`.
+    pub const WRITE: Protection = Protection(2);
+    pub const EXECUTE: Protection = Protection(4);
+
+    pub fn from_bits(bits: u64) -> Option<Protection> {
+        if bits & !0b111 != 0 {
+            return None;
+        }
+
+        Some(Protection(bits))
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The address or size isn't a multiple of the page size, or the size is zero.
+    Unaligned = 1,
+    /// The range isn't part of the caller's private address range.
+    OutsideUserSpace = 2,
+    /// The range overlaps an existing reservation.
+    Overlapping = 3,
+    /// Part of the range was never reserved.
+    NotReserved = 4,
+    OutOfMemory = 5,
+    InvalidProtection = 6,
+}
+
+impl MemoryError {
+    pub fn from_u64(num: u64) -> Option<MemoryError> {
+        match num {
+            1 => Some(MemoryError::Unaligned),
+            2 => Some(MemoryError::OutsideUserSpace),
+            3 => Some(MemoryError::Overlapping),
+            4 => Some(MemoryError::NotReserved),
+            5 => Some(MemoryError::OutOfMemory),
+            6 => Some(MemoryError::InvalidProtection),
+            _ => None,
+        }
+    }
+}
+
+/// The kernel reports the outcome of memory syscalls through this, since registers don't survive
+/// the return from the syscall. It has to live on the heap or in reserved memory.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryResult {
+    /// `0` on success, a `MemoryError` otherwise.
+    pub error: u64,
+    /// The start of the region for `SYSCALL_MEM_RESERVE`.
+    pub addr: u64,
+}
+
+/// Reserves `size` bytes of address space. Pages are backed with zeroed memory when they are
+/// first touched. If `addr` is `None`, the kernel picks a free spot.
+pub fn reserve(
+    addr: Option<*mut u8>,
+    size: usize,
+    protection: Protection,
+) -> Result<*mut u8, MemoryError> {
+    let addr = addr.map_or(0, |addr| addr as u64);
+    let result = unsafe { memory_syscall(SYSCALL_MEM_RESERVE, addr, size, protection.bits())? };
+
+    Ok(result.addr as *mut u8)
+}
+
+/// Backs every page of a reserved range right away, instead of on first access.
+pub fn map(addr: *mut u8, size: usize) -> Result<(), MemoryError> {
+    unsafe { memory_syscall(SYSCALL_MEM_MAP, addr as u64, size, 0)? };
+
+    Ok(())
+}
+
+/// Changes the access rights of a reserved range, including pages that are already backed.
+///
+/// # Safety
+/// Nothing may still access the range in a way `protection` doesn't allow.
+pub unsafe fn protect(
+    addr: *mut u8,
+    size: usize,
+    protection: Protection,
+) -> Result<(), MemoryError> {
+    memory_syscall(SYSCALL_MEM_PROTECT, addr as u64, size, protection.bits())?;
+
+    Ok(())
+}
+
+/// Releases a reserved range and the memory backing it.
+///
+/// # Safety
+/// Nothing may access the range afterwards.
+pub unsafe fn unmap(addr: *mut u8, size: usize) -> Result<(), MemoryError> {
+    memory_syscall(SYSCALL_MEM_UNMAP, addr as u64, size, 0)?;
+
+    Ok(())
+}
+
+unsafe fn memory_syscall(
+    number: u64,
+    addr: u64,
+    size: usize,
+    protection: u64,
+) -> Result<MemoryResult, MemoryError> {
+    // Boxed, since the kernel won't write to the stack and nothing is reserved before the first
+    // `reserve`.
+    let mut result = Box::new(MemoryResult::default());
+    syscall4(
+        number,
+        addr,
+        size as u64,
+        protection,
+        &mut *result as *mut MemoryResult as u64,
+    );
+
+    match MemoryError::from_u64(result.error) {
+        Some(error) => Err(error),
+        None => Ok(*result),
+    }
+}
+
+/// Memory usage of the whole system, as reported by `info`. All sizes are in bytes.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryInfo {
+    pub physical_total: u64,
+    pub physical_used: u64,
+    pub heap_size: u64,
+    pub heap_used: u64,
+    /// The heap grows on demand up to this size.
+    pub heap_limit: u64,
+    /// Memory handed to the slab allocator for small kernel allocations.
+    pub slab_size: u64,
+    pub slab_used: u64,
+    /// Address space for page allocations outside of the heap.
+    pub kernel_pages_size: u64,
+    pub kernel_pages_used: u64,
+    /// Address space for kernel stacks, including their guard pages.
+    pub kernel_stacks_size: u64,
+    pub kernel_stacks_used: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryKind {
+    Usable = 1,
+    /// Used by the bootloader, like the kernel image, page tables and boot info.
+    Bootloader = 2,
+    /// Reserved by the UEFI firmware, see `MemoryMapEntry::firmware_kind`.
+    Uefi = 3,
+    /// Reserved by the BIOS, see `MemoryMapEntry::firmware_kind`.
+    Bios = 4,
+    /// A kind of region the kernel doesn't know how to report.
+    Unknown = 5,
+}
+
+impl MemoryKind {
+    pub fn from_u32(num: u32) -> Option<MemoryKind> {
+        match num {
+            1 => Some(MemoryKind::Usable),
+            2 => Some(MemoryKind::Bootloader),
+            3 => Some(MemoryKind::Uefi),
+            4 => Some(MemoryKind::Bios),
+            5 => Some(MemoryKind::Unknown),
+            _ => None,
+        }
+    }
+}
+
+/// A region of physical memory from the bootloader's memory map.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub start: u64,
+    pub end: u64,
+    /// A `MemoryKind`.
+    pub kind: u32,
+    /// The memory type the firmware reported for `Uefi` and `Bios` regions.
+    pub firmware_kind: u32,
+}
+
+/// Reports how much memory the kernel uses.
+pub fn info() -> MemoryInfo {
+    let mut info = Box::new(MemoryInfo::default());
+    unsafe {
+        syscall4(
+            SYSCALL_MEM_INFO,
+            &mut *info as *mut MemoryInfo as u64,
+            0,
+            0,
+            0,
+        );
+    }
+
+    *info
+}
+
+/// Copies as much of the physical memory map into `entries` as fits and returns how many entries
+/// the map has in total. The kernel only fills in a `Buffer` or memory on the heap.
+pub fn memory_map(entries: &mut [MemoryMapEntry]) -> usize {
+    let mut count = Box::new(0u64);
+    unsafe {
+        syscall4(
+            SYSCALL_MEM_REGIONS,
+            entries.as_mut_ptr() as u64,
+            entries.len() as u64,
+            &mut *count as *mut u64 as u64,
+            0,
+        );
+    }
+
+    *count as usize
+}
+
+/// Values in pages of their own, reserved in the caller's address space. The kernel accepts these
+/// as the destination of syscalls, like the heap, without taking up room in it.
+pub struct Buffer<T: Copy + Default> {
+    values: *mut T,
+    len: usize,
+    /// The size of the reservation in bytes.
+    size: usize,
+}
+
+impl<T: Copy + Default> Buffer<T> {
+    /// Reserves room for `len` values, which start out as `T::default()`.
+    pub fn new(len: usize) -> Result<Self, MemoryError> {
+        let bytes = len
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(MemoryError::OutOfMemory)?;
+        let size = core::cmp::max(bytes, 1)
+            .checked_add(PAGE_SIZE - 1)
+            .ok_or(MemoryError::OutOfMemory)?
+            / PAGE_SIZE
+            * PAGE_SIZE;
+        let values = reserve(None, size, Protection::READ | Protection::WRITE)? as *mut T;
+
+        for i in 0..len {
+            unsafe { values.add(i).write(T::default()) };
+        }
+
+        Ok(Buffer { values, len, size })
+    }
+}
+
+impl<T: Copy + Default> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.values, self.len) }
+    }
+}
+
+impl<T: Copy + Default> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.values, self.len) }
+    }
+}
+
+impl<T: Copy + Default> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        // Nothing else refers to the pages, so this can only fail if the kernel lost track of them.
+        let _ = unsafe { unmap(self.values as *mut u8, self.size) };
+    }
+}
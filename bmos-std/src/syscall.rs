@@ -1,5 +1,16 @@
 use crate::io::IOChannel;
 
+pub const SYSCALL_PRINT: u64 = 1;
+pub const SYSCALL_MEM_RESERVE: u64 = 2;
+pub const SYSCALL_MEM_MAP: u64 = 3;
+pub const SYSCALL_MEM_PROTECT: u64 = 4;
+pub const SYSCALL_MEM_UNMAP: u64 = 5;
+pub const SYSCALL_MEM_INFO: u64 = 6;
+pub const SYSCALL_MEM_REGIONS: u64 = 7;
+pub const SYSCALL_SLEEP: u64 = 8;
+pub const SYSCALL_SET_FS_BASE: u64 = 9;
+pub const SYSCALL_THREADS: u64 = 10;
+
 macro_rules! syscall {
     ($expression:expr) => {
         asm!(
@@ -22,6 +33,21 @@ pub fn print(channel: IOChannel, string: &str) {
         length = in(reg) length,
         channel = in(reg) channel as i32,
         );
-        syscall!(1);
+        syscall!(SYSCALL_PRINT);
     }
 }
+
+/// Issues syscall `number` with up to four arguments in rdi, rsi, rdx and r10.
+///
+/// # Safety
+/// The arguments have to be valid for the given syscall.
+pub(crate) unsafe fn syscall4(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64) {
+    asm!(
+    "int 0x80",
+    in("rax") number,
+    in("rdi") arg0,
+    in("rsi") arg1,
+    in("rdx") arg2,
+    in("r10") arg3,
+    );
+}
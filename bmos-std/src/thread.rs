@@ -0,0 +1,121 @@
+use crate::syscall::{syscall4, SYSCALL_SET_FS_BASE, SYSCALL_SLEEP, SYSCALL_THREADS};
+use alloc::boxed::Box;
+use core::time::Duration;
+
+pub use local::{drop_thread_locals, LocalKey};
+
+mod local;
+
+/// Blocks the calling thread for at least `duration`. The kernel's timer has a resolution of
+/// 10 ms, so shorter sleeps are rounded up.
+pub fn sleep(duration: Duration) {
+    unsafe {
+        syscall4(
+            SYSCALL_SLEEP,
+            duration.as_secs(),
+            duration.subsec_nanos() as u64,
+            0,
+            0,
+        );
+    }
+}
+
+/// Points the FS base register of the calling thread at `address`.
+///
+/// # Safety
+/// `LocalKey` keeps the thread's values in the word at FS:0, so `address` has to point to a word
+/// that is null or was at FS:0 before. Values that were reachable through the old one are leaked.
+pub unsafe fn set_fs_base(address: u64) {
+    syscall4(SYSCALL_SET_FS_BASE, address, 0, 0, 0);
+}
+
+/// Names in `ThreadInfo` are cut off after this many bytes.
+pub const THREAD_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting for a CPU.
+    Ready = 1,
+    Running = 2,
+    /// Waiting for something else, like a lock, input or the timer.
+    Blocked = 3,
+    /// Finished, but not freed yet.
+    Dead = 4,
+}
+
+impl ThreadState {
+    pub fn from_u32(num: u32) -> Option<ThreadState> {
+        match num {
+            1 => Some(ThreadState::Ready),
+            2 => Some(ThreadState::Running),
+            3 => Some(ThreadState::Blocked),
+            4 => Some(ThreadState::Dead),
+            _ => None,
+        }
+    }
+}
+
+/// A thread, as `threads` reports it. Times are in timer ticks, see `ThreadClock`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: u64,
+    /// UTF-8, padded with zeroes.
+    pub name: [u8; THREAD_NAME_LENGTH],
+    /// A `ThreadState`.
+    pub state: u32,
+    /// The CPU the thread runs on, or will run on next.
+    pub cpu: u32,
+    /// Ticks during which the thread was running.
+    pub ticks: u64,
+    /// How often the kernel switched to the thread.
+    pub context_switches: u64,
+    /// Ticks since boot when the thread was created.
+    pub created_at: u64,
+}
+
+impl ThreadInfo {
+    pub fn name(&self) -> &str {
+        let length = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(THREAD_NAME_LENGTH);
+        core::str::from_utf8(&self.name[..length]).unwrap_or("?")
+    }
+}
+
+/// The kernel's timer when `threads` took its snapshot.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadClock {
+    /// Ticks since boot.
+    pub ticks: u64,
+    pub ticks_per_second: u64,
+}
+
+impl ThreadClock {
+    /// Converts a number of ticks, like `ThreadInfo::ticks`, to seconds.
+    pub fn seconds(&self, ticks: u64) -> f64 {
+        ticks as f64 / self.ticks_per_second as f64
+    }
+}
+
+/// Copies as much of a snapshot of all threads into `entries` as fits. Returns how many threads
+/// there are in total and the timer when the snapshot was taken. The kernel only fills in a
+/// `mem::Buffer` or memory on the heap.
+pub fn threads(entries: &mut [ThreadInfo]) -> (usize, ThreadClock) {
+    let mut count = Box::new(0u64);
+    let mut clock = Box::new(ThreadClock::default());
+    unsafe {
+        syscall4(
+            SYSCALL_THREADS,
+            entries.as_mut_ptr() as u64,
+            entries.len() as u64,
+            &mut *count as *mut u64 as u64,
+            &mut *clock as *mut ThreadClock as u64,
+        );
+    }
+
+    (*count as usize, *clock)
+}
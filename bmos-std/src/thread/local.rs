@@ -0,0 +1,144 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Every thread's values, indexed by the slot of their key. FS:0 points to it, or is null while
+/// the thread hasn't used any thread-local variable yet.
+type Table = Vec<Option<Box<dyn Any>>>;
+
+/// Declares thread-local variables, which are `LocalKey`s. Every thread gets its own value,
+/// initialized the first time the thread uses the variable.
+///
+/// ```ignore
+/// thread_local! {
+///     static COUNTER: Cell<u32> = Cell::new(0);
+/// }
+///
+/// COUNTER.with(|counter| counter.set(counter.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])*
+        $vis static $name: $crate::thread::LocalKey<$t> = {
+            fn __init() -> $t {
+                $init
+            }
+
+            $crate::thread::LocalKey {
+                __init,
+                __slot: ::core::sync::atomic::AtomicUsize::new(0),
+            }
+        };
+    };
+}
+
+/// A thread-local variable, declared with `thread_local!`.
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub __init: fn() -> T,
+    /// The index of the values in every thread's table plus one, zero until the first thread
+    /// used the variable.
+    #[doc(hidden)]
+    pub __slot: AtomicUsize,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Calls `f` with the calling thread's value, which is initialized first if the thread
+    /// hasn't used it yet.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let slot = self.slot();
+        let value = match self.get(slot) {
+            Some(value) => value,
+            None => {
+                // The initializer may use other thread-local variables, which can grow the table.
+                let value = Box::new((self.__init)());
+                let table = unsafe { &mut *table() };
+                if table.len() <= slot {
+                    table.resize_with(slot + 1, || None);
+                }
+                table[slot] = Some(value);
+                self.get(slot).unwrap()
+            }
+        };
+
+        // The value stays where it is until the thread ends, even if the table grows.
+        f(unsafe { &*value })
+    }
+
+    fn get(&self, slot: usize) -> Option<*const T> {
+        let table = unsafe { &*table() };
+        let value = table.get(slot)?.as_ref()?;
+        Some(value.downcast_ref::<T>().unwrap() as *const T)
+    }
+
+    fn slot(&self) -> usize {
+        static NEXT_SLOT: AtomicUsize = AtomicUsize::new(1);
+
+        let slot = self.__slot.load(Ordering::Acquire);
+        if slot != 0 {
+            return slot - 1;
+        }
+
+        // Another thread may use the variable for the first time as well, then one of the slots
+        // stays unused.
+        let new_slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        match self
+            .__slot
+            .compare_exchange(0, new_slot, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => new_slot - 1,
+            Err(slot) => slot - 1,
+        }
+    }
+}
+
+/// The calling thread's table, created the first time it is needed.
+fn table() -> *mut Table {
+    let mut table = read_root();
+    if table.is_null() {
+        table = Box::into_raw(Box::new(Table::new()));
+        write_root(table);
+    }
+
+    table
+}
+
+/// Drops the calling thread's thread-local values. The kernel calls this when a thread returns
+/// from its function.
+pub fn drop_thread_locals() {
+    let table = read_root();
+    if !table.is_null() {
+        // Values that use thread-local variables while they are dropped get a new table, which
+        // is leaked.
+        write_root(core::ptr::null_mut());
+        drop(unsafe { Box::from_raw(table) });
+    }
+}
+
+// The kernel gives every thread a zeroed word at FS:0, and FS base only changes when the thread
+// switches, so these can't be interrupted halfway.
+
+fn read_root() -> *mut Table {
+    let table: *mut Table;
+    unsafe {
+        asm!("mov {}, fs:[0]", out(reg) table, options(nostack, preserves_flags, readonly));
+    }
+
+    table
+}
+
+fn write_root(table: *mut Table) {
+    unsafe {
+        asm!("mov fs:[0], {}", in(reg) table, options(nostack, preserves_flags));
+    }
+}
@@ -9,4 +9,4 @@ if [ $? -ne 0 ]; then
     echo "Failed to build bootable image, check errors above."
     exit
 fi
-qemu-system-x86_64 -S -s -drive format=raw,file=target/x86_64-bmos/debug/boot-bios-bmos.img  -d cpu_reset -serial stdio -m 1G
+qemu-system-x86_64 -S -s -drive format=raw,file=target/x86_64-bmos/debug/boot-bios-bmos.img  -d cpu_reset -serial stdio -m 1G -smp 4 -fw_cfg name=opt/bmos/scheduler,string=${BMOS_SCHEDULER:-mlfq} -fw_cfg name=opt/bmos/selftest,string=${BMOS_SELFTEST:-0}
@@ -0,0 +1,123 @@
+use crate::memory;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const SDT_HEADER_SIZE: u64 = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    InvalidRsdp,
+    /// The table with this signature is corrupted.
+    InvalidChecksum([u8; 4]),
+    /// The table with this signature is too short to hold its own header.
+    InvalidLength([u8; 4]),
+    /// There is no table with this signature.
+    TableNotFound([u8; 4]),
+}
+
+/// What the MADT tells about the local APICs.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// APIC IDs of every processor that is enabled or can be brought online, the bootstrap
+    /// processor included.
+    pub apic_ids: Vec<u8>,
+}
+
+/// Finds the MADT through the RSDP at `rsdp_addr` and parses it.
+pub fn parse_madt(rsdp_addr: PhysAddr) -> Result<Madt, AcpiError> {
+    let madt = find_table(rsdp_addr, b"APIC")?;
+    let length = read::<u32>(madt + 4u64) as u64;
+
+    let mut result = Madt {
+        local_apic_address: PhysAddr::new(read::<u32>(madt + SDT_HEADER_SIZE) as u64),
+        apic_ids: Vec::new(),
+    };
+
+    // The entries follow the local APIC address and the flags.
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= length {
+        let entry = madt + offset;
+        let entry_type = read::<u8>(entry);
+        let entry_length = read::<u8>(entry + 1u64) as u64;
+        if entry_length < 2 {
+            break;
+        }
+
+        match entry_type {
+            // Processor local APIC
+            0 => {
+                let flags = read::<u32>(entry + 4u64);
+                // Enabled, or online capable
+                if flags & 0b11 != 0 {
+                    result.apic_ids.push(read(entry + 3u64));
+                }
+            }
+            // Local APIC address override
+            5 => result.local_apic_address = PhysAddr::new(read(entry + 4u64)),
+            _ => {}
+        }
+
+        offset += entry_length;
+    }
+
+    Ok(result)
+}
+
+/// Looks up the table with `signature` in the XSDT, or in the RSDT on ACPI 1.0 systems.
+fn find_table(rsdp_addr: PhysAddr, signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    if &read::<[u8; 8]>(rsdp_addr) != b"RSD PTR " || !checksum_valid(rsdp_addr, 20) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let revision = read::<u8>(rsdp_addr + 15u64);
+    let (root, entry_size) = if revision >= 2 {
+        (PhysAddr::new(read(rsdp_addr + 24u64)), 8)
+    } else {
+        (PhysAddr::new(read::<u32>(rsdp_addr + 16u64) as u64), 4)
+    };
+    check_table(root)?;
+
+    let length = read::<u32>(root + 4u64) as u64;
+    let entries = (length - SDT_HEADER_SIZE) / entry_size;
+    for index in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + index * entry_size;
+        let table = if entry_size == 8 {
+            PhysAddr::new(read(entry))
+        } else {
+            PhysAddr::new(read::<u32>(entry) as u64)
+        };
+
+        if &read::<[u8; 4]>(table) == signature {
+            check_table(table)?;
+            return Ok(table);
+        }
+    }
+
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+fn check_table(table: PhysAddr) -> Result<(), AcpiError> {
+    let length = read::<u32>(table + 4u64) as u64;
+    if length < SDT_HEADER_SIZE {
+        Err(AcpiError::InvalidLength(read(table)))
+    } else if checksum_valid(table, length) {
+        Ok(())
+    } else {
+        Err(AcpiError::InvalidChecksum(read(table)))
+    }
+}
+
+/// All bytes of a table have to add up to zero.
+fn checksum_valid(addr: PhysAddr, length: u64) -> bool {
+    (0..length)
+        .map(|offset| read::<u8>(addr + offset))
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte))
+        == 0
+}
+
+/// ACPI tables are packed, so every field is read unaligned through the physical memory mapping.
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    let ptr = memory::physical_to_virtual(addr).as_ptr::<T>();
+    unsafe { ptr.read_unaligned() }
+}
@@ -0,0 +1,138 @@
+use crate::memory;
+use crate::timer;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Vector of interrupts the local APIC raises when an interrupt went away before it could be
+/// delivered. They don't need an end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const END_OF_INTERRUPT: u64 = 0xb0;
+const SPURIOUS_INTERRUPT: u64 = 0xf0;
+const INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+const TIMER_VECTOR: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+const APIC_ENABLE: u32 = 1 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_MASKED: u32 = 1 << 16;
+/// Divides the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Where the registers of the local APIC are mapped. Every CPU sees its own APIC at the same
+/// address. Zero until `init` ran.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Timer counts per timer tick, measured against the PIT by `calibrate_timer`.
+static TIMER_COUNTS_PER_TICK: AtomicU32 = AtomicU32::new(0);
+
+/// Maps the local APIC's registers at `address`, which the MADT tells, and enables the bootstrap
+/// processor's local APIC.
+pub fn init(address: PhysAddr) {
+    let base = memory::map_device_memory(address).expect("Out of memory");
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    enable();
+}
+
+/// Enables the local APIC of the calling CPU.
+pub fn enable() {
+    write(TASK_PRIORITY, 0);
+    write(SPURIOUS_INTERRUPT, APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// The APIC ID of the calling CPU.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write(END_OF_INTERRUPT, 0);
+}
+
+/// Resets the CPU with `apic_id`, it then waits for a startup IPI.
+pub fn send_init(apic_id: u8) {
+    send_command(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Starts the CPU with `apic_id` in real mode at the start of the physical page `page`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_command(apic_id, DELIVERY_STARTUP | page as u32);
+}
+
+/// Raises interrupt `vector` on the CPU with `apic_id`.
+pub fn send_interrupt(apic_id: u8, vector: u8) {
+    send_command(apic_id, LEVEL_ASSERT | vector as u32);
+}
+
+fn send_command(apic_id: u8, command: u32) {
+    // An interrupt handler that sends an IPI must not get in between the two writes.
+    without_interrupts(|| {
+        write(INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+        // Writing the low half sends the interrupt.
+        write(INTERRUPT_COMMAND_LOW, command);
+
+        while read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Measures how fast the local APIC timer runs, so `start_timer` can make it tick as often as
+/// the PIT. Needs timer interrupts, and takes a few ticks.
+pub fn calibrate_timer() {
+    const TICKS: u64 = 10;
+
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(TIMER_VECTOR, TIMER_MASKED);
+
+    // Start right at the beginning of a tick.
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        core::hint::spin_loop();
+    }
+
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    while timer::ticks() < start + 1 + TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+    write(TIMER_INITIAL_COUNT, 0);
+
+    TIMER_COUNTS_PER_TICK.store(elapsed / TICKS as u32, Ordering::Relaxed);
+}
+
+/// Makes the calling CPU's local APIC raise interrupt `vector` once per timer tick.
+pub fn start_timer(vector: u8) {
+    let counts = TIMER_COUNTS_PER_TICK.load(Ordering::Relaxed);
+    assert!(counts > 0, "The local APIC timer isn't calibrated");
+
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(TIMER_VECTOR, TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL_COUNT, counts);
+}
+
+fn register(offset: u64) -> *mut u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0, "The local APIC isn't mapped");
+
+    VirtAddr::new(base + offset).as_mut_ptr()
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
@@ -8,6 +8,24 @@ __switch_context:
     pushq %r15
     pushq %rbx
     pushq %rbp
+
+    # The kernel doesn't touch the x87, SSE and AVX registers, so the new thread's can be loaded
+    # right away. The save areas follow the stack pointers in the threads.
+    movq 8(%rdi), %r8
+    movq 8(%rsi), %r9
+    cmpb $0, FPU_USES_XSAVE(%rip)
+    je 1f
+    # Every component enabled in XCR0
+    movl $0xffffffff, %eax
+    movl $0xffffffff, %edx
+    xsave64 (%r8)
+    xrstor64 (%r9)
+    jmp 2f
+1:
+    fxsave64 (%r8)
+    fxrstor64 (%r9)
+2:
+
     movq %rsp, (%rdi)
     movq (%rsi), %rsp
     popq %rbp
@@ -17,6 +35,5 @@ __switch_context:
     popq %r13
     popq %r12
     movq %rsi, %rdi
-    sti
     ret
 
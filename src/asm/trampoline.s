@@ -0,0 +1,113 @@
+# Startup code of the application processors. It is copied to a page below 1 MiB, where the
+# processors start in real mode after the startup IPI, with CS pointing at that page. All
+# addresses are therefore relative to ap_trampoline, and the absolute ones are computed from
+# ap_trampoline_base, which the kernel fills in along with the other fields at the end.
+
+.global ap_trampoline
+.global ap_trampoline_end
+.global ap_trampoline_base
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu
+
+.code16
+ap_trampoline:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+
+    # The GDT pointer and the far jump need physical addresses.
+    movl (ap_trampoline_base - ap_trampoline), %ebx
+    leal (ap_gdt - ap_trampoline)(%ebx), %eax
+    movl %eax, (ap_gdt_pointer - ap_trampoline + 2)
+    leal (ap_protected_mode - ap_trampoline)(%ebx), %eax
+    movl %eax, (ap_far_jump - ap_trampoline)
+    lgdtl (ap_gdt_pointer - ap_trampoline)
+
+    # Protected mode
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl *(ap_far_jump - ap_trampoline)
+
+.code32
+ap_protected_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+
+    # PAE, and the kernel's page tables
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline)(%ebx), %eax
+    movl %eax, %cr3
+
+    # Long mode and no-execute pages, in the EFER
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    # Paging and write protection. The trampoline is identity mapped, so we keep running.
+    movl %cr0, %eax
+    orl $0x80010000, %eax
+    movl %eax, %cr0
+
+    leal (ap_long_mode - ap_trampoline)(%ebx), %eax
+    movl %eax, (ap_far_jump - ap_trampoline)(%ebx)
+    movw $0x18, (ap_far_jump - ap_trampoline + 4)(%ebx)
+    ljmpl *(ap_far_jump - ap_trampoline)(%ebx)
+
+.code64
+ap_long_mode:
+    xorl %eax, %eax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+
+    # The upper halves of the registers are undefined after entering long mode.
+    movl %ebx, %ebx
+    movq (ap_trampoline_stack - ap_trampoline)(%rbx), %rsp
+    movq (ap_trampoline_cpu - ap_trampoline)(%rbx), %rdi
+    movq (ap_trampoline_entry - ap_trampoline)(%rbx), %rax
+    callq *%rax
+
+1:
+    hlt
+    jmp 1b
+
+ap_gdt:
+    .quad 0
+    # 32-bit code
+    .quad 0x00cf9a000000ffff
+    # Data
+    .quad 0x00cf92000000ffff
+    # 64-bit code
+    .quad 0x00af9a000000ffff
+ap_gdt_end:
+
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long 0
+
+ap_far_jump:
+    .long 0
+    .word 0x08
+
+ap_trampoline_base:
+    .quad 0
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu:
+    .quad 0
+ap_trampoline_end:
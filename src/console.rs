@@ -183,6 +183,13 @@ impl<'a> Console<'a> {
         });
     }
 
+    /// Moves every line up by one, dropping the topmost one. The bottom line ends up empty.
+    pub fn scroll_up(&self) {
+        let mut screen_buffer = self.screen_buffer.lock();
+        screen_buffer.remove(0);
+        screen_buffer.push(vec![None; self.width as usize]);
+    }
+
     pub fn delete_char(&self, x: u32, y: u32) {
         let mut screen_buffer = self.screen_buffer.lock();
         let mut current_row = screen_buffer.get_mut(y as usize).unwrap();
@@ -38,6 +38,15 @@ pub fn read_rdx() -> u64 {
     rdx_value
 }
 
+pub fn read_r10() -> u64 {
+    let r10_value: u64;
+    unsafe {
+        asm!("mov {}, r10", out(reg) r10_value, options(nomem));
+    }
+
+    r10_value
+}
+
 pub fn write_rax(rax_value: u64) {
     unsafe {
         asm!("mov rax, {}", in(reg) rax_value, options(nomem));
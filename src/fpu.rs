@@ -0,0 +1,110 @@
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+pub mod selftest;
+
+/// Set if `__switch_context` saves the extended state with XSAVE. Otherwise it falls back to
+/// FXSAVE, which only covers the x87 and SSE registers.
+#[no_mangle]
+static FPU_USES_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Size of every save area. FXSAVE always takes 512 bytes, XSAVE depends on the enabled features.
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+const FXSAVE_AREA_SIZE: usize = 512;
+/// XSAVE needs 64 bytes, FXSAVE 16.
+const AREA_ALIGN: usize = 64;
+
+/// The x87 control word and MXCSR after a reset, with every exception masked.
+const DEFAULT_FPU_CONTROL: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Enables the x87 FPU, SSE and, if the CPU has it, AVX on the calling CPU.
+///
+/// The kernel itself is built without SSE and never touches these registers, so interrupt
+/// handlers and syscalls leave them alone. Only `__switch_context` saves and restores them, for
+/// every thread on every switch. The bootstrap processor has to run this before the first thread
+/// is built, since it decides how big the save areas are.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let features = unsafe { __cpuid(1) };
+    if features.ecx & CPUID_XSAVE == 0 {
+        return;
+    }
+
+    let mut enabled = XCR0_X87 | XCR0_SSE;
+    if features.ecx & CPUID_AVX != 0 {
+        enabled |= XCR0_AVX;
+    }
+    unsafe {
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+        write_xcr0(enabled);
+    }
+
+    // The size XSAVE needs for everything that is enabled in XCR0 right now.
+    let size = unsafe { __cpuid_count(0xd, 0) }.ebx as usize;
+    AREA_SIZE.store(size, Ordering::Relaxed);
+    FPU_USES_XSAVE.store(true, Ordering::Relaxed);
+}
+
+unsafe fn write_xcr0(value: u64) {
+    asm!(
+        "xsetbv",
+        in("ecx") 0,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nomem, nostack)
+    );
+}
+
+/// Where a thread's x87, SSE and AVX registers are kept while it isn't running.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct ExtendedState(*mut u8);
+
+impl ExtendedState {
+    /// A save area that loads the registers in their initial state, with every floating point
+    /// exception masked.
+    pub fn new() -> Self {
+        let layout = area_layout();
+        unsafe {
+            let area = alloc_zeroed(layout);
+            if area.is_null() {
+                handle_alloc_error(layout);
+            }
+
+            // XRSTOR resets everything else, since the XSAVE header is all zeroes.
+            (area as *mut u16).write(DEFAULT_FPU_CONTROL);
+            (area.add(MXCSR_OFFSET) as *mut u32).write(DEFAULT_MXCSR);
+
+            Self(area)
+        }
+    }
+}
+
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.0, area_layout()) };
+    }
+}
+
+fn area_layout() -> Layout {
+    Layout::from_size_align(AREA_SIZE.load(Ordering::Relaxed), AREA_ALIGN).unwrap()
+}
@@ -0,0 +1,21 @@
+use crate::selftest::{join_all, spawn_contenders};
+use crate::threading;
+
+/// Keeps a different value in an SSE register in each of several threads while they take turns,
+/// so the register only survives if context switches save and restore it.
+pub fn extended_state() {
+    const ROUNDS: usize = 50;
+
+    join_all(spawn_contenders("selftest-sse", |index| {
+        let value = 0x5353_4500 + index as u64;
+        for _ in 0..ROUNDS {
+            // The kernel is built without SSE, so nothing else uses xmm0.
+            unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+            threading::yield_now();
+
+            let saved: u64;
+            unsafe { asm!("movq {}, xmm0", out(reg) saved, options(nomem, nostack)) };
+            assert_eq!(saved, value, "xmm0 was clobbered by another thread");
+        }
+    }));
+}
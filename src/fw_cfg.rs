@@ -0,0 +1,90 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+
+/// Length of the zero padded file names in the file directory.
+const NAME_LENGTH: usize = 56;
+
+/// Reads the file `name` that was passed to QEMU with `-fw_cfg name=<name>,string=<value>`.
+/// Returns `None` if there is no such file, or if we don't run in QEMU at all.
+///
+/// This is the closest thing to a kernel command line the bootloader leaves us with.
+pub fn read_string(name: &str) -> Option<String> {
+    let data = read_file(name)?;
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+
+    String::from_utf8(data[..end].to_vec()).ok()
+}
+
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    if !is_present() {
+        return None;
+    }
+
+    select(FILE_DIR);
+    let count = read_u32();
+    for _ in 0..count {
+        let size = read_u32();
+        let selector = read_u16();
+        let _reserved = read_u16();
+        let mut file_name = [0; NAME_LENGTH];
+        read(&mut file_name);
+
+        let name_end = file_name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(NAME_LENGTH);
+        if &file_name[..name_end] == name.as_bytes() {
+            select(selector);
+            let mut data = vec![0; size as usize];
+            read(&mut data);
+
+            return Some(data);
+        }
+    }
+
+    None
+}
+
+fn is_present() -> bool {
+    select(SIGNATURE);
+    let mut signature = [0; 4];
+    read(&mut signature);
+
+    &signature == b"QEMU"
+}
+
+fn select(selector: u16) {
+    let mut port: Port<u16> = Port::new(SELECTOR_PORT);
+    unsafe { port.write(selector) };
+}
+
+fn read(buffer: &mut [u8]) {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    for byte in buffer {
+        *byte = unsafe { port.read() };
+    }
+}
+
+/// The file directory is big endian, unlike everything else on x86.
+fn read_u32() -> u32 {
+    let mut bytes = [0; 4];
+    read(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_u16() -> u16 {
+    let mut bytes = [0; 2];
+    read(&mut bytes);
+    u16::from_be_bytes(bytes)
+}
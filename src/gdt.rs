@@ -1,3 +1,5 @@
+use crate::memory;
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,19 +7,19 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 8192;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 8192;
-
             #[repr(align(16))]
-            struct Stack([u8; STACK_SIZE]);
+            struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+            static mut STACK: Stack = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             stack_end
         };
         tss
@@ -25,18 +27,7 @@ lazy_static! {
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
 struct Selectors {
@@ -44,13 +35,43 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
+}
+
 pub fn init() {
+    load(&GDT);
+}
+
+/// Gives an application processor its own GDT and TSS. The CPU marks the TSS it loads as busy,
+/// and every CPU needs its own stack for double faults anyway.
+pub fn init_ap() {
+    let stack = memory::allocate_kernel_stack(DOUBLE_FAULT_STACK_SIZE).expect("Out of memory");
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top();
+
+    // Both live as long as the CPU does.
+    let tss = Box::leak(Box::new(tss));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,17 @@
+use crate::apic;
 use crate::cpu;
 use crate::debug;
-use crate::keyboard::{KeyEvent, KEYBOARD_REGISTRY};
-use crate::serial::SERIAL;
-use crate::{CONSOLE, SCHEDULER, TERMINAL};
-use bmos_std::io::IOChannel;
+use crate::gdt;
+use crate::keyboard;
+use crate::keyboard::KeyEvent;
+use crate::memory;
+use crate::memory::PageFaultError;
+use crate::scheduler;
+use crate::smp;
+use crate::syscall;
+use crate::threading;
+use crate::threading::PanicMessage;
+use crate::timer;
 use core::fmt::Write;
 use lazy_static::lazy_static;
 use pc_keyboard::layouts::Us104Key;
@@ -11,8 +19,11 @@ use pc_keyboard::{HandleControl, Keyboard, ScancodeSet1};
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -24,7 +35,13 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        // A stack overflow leaves no usable stack behind, so the double fault it escalates to
+        // has to run on its own stack.
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
         idt.page_fault.set_handler_fn(segfault_handler);
         idt.invalid_opcode.set_handler_fn(opcode_handler);
 
@@ -33,6 +50,12 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
         idt[InterruptIndex::Syscall.as_usize()].set_handler_fn(syscall_handler);
 
+        // Interrupts of the local APICs, once the other CPUs are started
+        idt[InterruptIndex::LocalTimer.as_usize()].set_handler_fn(local_timer_handler);
+        idt[InterruptIndex::Reschedule.as_usize()].set_handler_fn(reschedule_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+
         idt
     };
 
@@ -45,11 +68,17 @@ lazy_static! {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    /// Drives the scheduler on the CPUs that don't get the PIT's interrupts.
+    LocalTimer = 0x30,
+    /// Sent to a CPU when there is something in its scheduler's inbox.
+    Reschedule = 0x31,
+    /// Sent to a CPU that has to flush its TLB, see `smp::tlb_shootdown`.
+    TlbShootdown = 0x32,
     Syscall = 0x80,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -62,10 +91,29 @@ extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-        SCHEDULER.as_mut().unwrap().tick();
     }
+    timer::tick();
+    scheduler::tick();
+}
+
+extern "x86-interrupt" fn local_timer_handler(stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+    scheduler::tick();
+}
+
+extern "x86-interrupt" fn reschedule_handler(stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+    scheduler::handle_reschedule();
 }
 
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+    smp::handle_tlb_shootdown();
+}
+
+/// Spurious interrupts don't get an end of interrupt.
+extern "x86-interrupt" fn spurious_handler(stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn opcode_handler(stack_frame: InterruptStackFrame) {
     panic!("INVALID OPCODE");
 }
@@ -74,42 +122,66 @@ extern "x86-interrupt" fn segfault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    panic!("SEGFAULT: {:?}, error: {:?}", stack_frame, error_code);
-}
+    let fault_addr = Cr2::read();
+    check_stack_overflow(fault_addr);
 
-extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
-    let syscall_number = cpu::read_rax();
-    match syscall_number {
-        1 => {
-            // print()
-            let data_start = cpu::read_rdi() as *const u8;
-            let length = cpu::read_rsi();
-            let io_channel = IOChannel::from_u32(cpu::read_rdx() as u32).unwrap();
-            debug!(
-                "Arguments: data_start = {:#?}, length = {}, io_channel = {:?}",
-                data_start, length, io_channel
+    match memory::current_address_space().handle_page_fault(fault_addr, error_code) {
+        Ok(()) => return,
+        // Only the thread that wanted the memory has to go.
+        Err(PageFaultError::OutOfMemory) => {
+            let mut message = PanicMessage::new();
+            let _ = write!(
+                message,
+                "Out of memory while handling page fault at {:?}",
+                fault_addr
             );
+            debug!("{}, terminating thread", message.as_str());
+            threading::terminate(message);
+        }
+        Err(PageFaultError::AccessViolation) => {}
+    }
 
-            let data_slice = unsafe { core::slice::from_raw_parts(data_start, length as usize) };
-            let string = core::str::from_utf8(data_slice);
-            debug!("String Result: {:?}", string);
-
-            match io_channel {
-                IOChannel::Stdout => {
-                    let cursor = unsafe { TERMINAL.as_ref().unwrap().cursor_position() };
-                    let console = unsafe { CONSOLE.as_ref().unwrap() };
-
-                    console.print(string.unwrap(), cursor.column, cursor.row);
-                }
-                IOChannel::Serial => {
-                    let mut serial = SERIAL.lock();
-                    serial.write_str(string.unwrap()).unwrap();
-                }
-            }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if let Some(section) = memory::kernel_section(fault_addr) {
+            let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                "write to"
+            } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                "execution of"
+            } else {
+                "access to"
+            };
+            panic!(
+                "W^X violation: {} kernel {} at {:?}\n{:#?}",
+                access, section, fault_addr, stack_frame
+            );
         }
-        _ => debug!("INVALID SYSCALL NUMBER"),
     }
-    debug!("SYSCALL: {}", syscall_number);
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        debug!(
+            "SEGFAULT at {:?} in user mode, terminating thread. {:?}, error: {:?}",
+            fault_addr, stack_frame, error_code
+        );
+        threading::exit();
+    }
+
+    panic!(
+        "SEGFAULT at {:?}: {:?}, error: {:?}",
+        fault_addr, stack_frame, error_code
+    );
+}
+
+extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
+    // Read the registers before anything else gets a chance to clobber them.
+    let syscall_number = cpu::read_rax();
+    let arguments = [
+        cpu::read_rdi(),
+        cpu::read_rsi(),
+        cpu::read_rdx(),
+        cpu::read_r10(),
+    ];
+
+    syscall::dispatch(syscall_number, arguments);
 }
 
 extern "x86-interrupt" fn keyboard_handler(stack_frame: InterruptStackFrame) {
@@ -128,13 +200,10 @@ extern "x86-interrupt" fn keyboard_handler(stack_frame: InterruptStackFrame) {
         }
     };
 
-    let event = KeyEvent::new(code, state, key);
+    // Handlers may block, so they run in the keyboard thread instead of here.
+    keyboard::queue_event(KeyEvent::new(code, state, key));
 
     unsafe {
-        if let Some(registry) = &KEYBOARD_REGISTRY {
-            registry.dispatch_event(event);
-        }
-
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
@@ -148,14 +217,54 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    check_stack_overflow(Cr2::read());
+
     panic!(
         "EXCEPTION: DOUBLE FAULT, Error Code: {}\n{:#?}",
         error_code, stack_frame
     );
 }
 
+/// Panics with a descriptive message if `fault_addr` lies in the guard page of a kernel stack.
+fn check_stack_overflow(fault_addr: VirtAddr) {
+    let is_guard_page = memory::is_guard_page(fault_addr);
+    if is_guard_page == Some(false) {
+        return;
+    }
+
+    let current_task =
+        unsafe { scheduler::try_local() }.and_then(|scheduler| scheduler.current_task());
+    if let Some(task) = current_task {
+        let guard_page = task.stack.map(|stack| stack.guard_page());
+        if guard_page == Some(Page::containing_address(fault_addr)) {
+            panic!(
+                "stack overflow in thread '{}' (guard page hit at {:?})",
+                task.name, fault_addr
+            );
+        }
+    }
+
+    match is_guard_page {
+        Some(_) => panic!("Kernel stack guard page hit at {:?}", fault_addr),
+        None => panic!(
+            "Fault at {:?} in the kernel stack window, probably a guard page",
+            fault_addr
+        ),
+    }
+}
+
 pub fn init() {
     IDT.load();
     unsafe { PICS.lock().initialize() };
+    timer::init();
     x86_64::instructions::interrupts::enable();
 }
+
+/// Sets up interrupts on an application processor. The PIC's interrupts only go to the bootstrap
+/// processor, so the local APIC's timer drives the scheduler here. Interrupts stay disabled until
+/// the CPU runs its first thread.
+pub fn init_ap() {
+    IDT.load();
+    apic::enable();
+    apic::start_timer(InterruptIndex::LocalTimer.as_u8());
+}
@@ -1,12 +1,110 @@
 use crate::debug;
+use crate::scheduler;
+use crate::threading;
+use crate::threading::{Priority, ThreadId};
 use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 
 type Handler<'a> = &'a (dyn KeyboardHandler + Send + Sync);
 
 pub static mut KEYBOARD_REGISTRY: Option<KeyboardEventRegistry<'static>> = None;
 
+/// Events the interrupt handler received, but the keyboard thread didn't dispatch yet.
+/// Only locked with interrupts disabled, since the interrupt handler locks it as well.
+static EVENT_QUEUE: Mutex<EventQueue> = Mutex::new(EventQueue::new());
+
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// Sets up the registry and starts the keyboard thread, which hands every key event to the
+/// registered handlers.
 pub fn init() {
     unsafe { KEYBOARD_REGISTRY = Some(KeyboardEventRegistry::new()) };
+
+    // The terminal has to react quickly, no matter what else is running.
+    threading::Builder::new("keyboard")
+        .priority(Priority::High)
+        .spawn(|| loop {
+            let event = next_event();
+            unsafe {
+                if let Some(registry) = &KEYBOARD_REGISTRY {
+                    registry.dispatch_event(event);
+                }
+            }
+        });
+}
+
+/// Hands `event` to the keyboard thread. Called by the keyboard interrupt handler.
+pub fn queue_event(event: KeyEvent) {
+    let mut queue = EVENT_QUEUE.lock();
+    if !queue.push(event) {
+        debug!("Keyboard event queue is full, dropping {:?}", event);
+    }
+
+    if let Some(waiter) = queue.waiter.take() {
+        scheduler::wake(waiter);
+    }
+}
+
+/// Blocks until there is a key event.
+fn next_event() -> KeyEvent {
+    loop {
+        let event = without_interrupts(|| {
+            let mut queue = EVENT_QUEUE.lock();
+            let event = queue.pop();
+            if event.is_none() {
+                let scheduler = unsafe { scheduler::local() };
+                queue.waiter = scheduler.current_task().map(|task| task.id);
+                drop(queue);
+                scheduler.block_current();
+            }
+
+            event
+        });
+
+        if let Some(event) = event {
+            return event;
+        }
+    }
+}
+
+/// A fixed-size ring buffer, so the interrupt handler never has to allocate.
+struct EventQueue {
+    events: [Option<KeyEvent>; EVENT_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    /// The keyboard thread, if it is waiting for events.
+    waiter: Option<ThreadId>,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        Self {
+            events: [None; EVENT_QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            waiter: None,
+        }
+    }
+
+    fn push(&mut self, event: KeyEvent) -> bool {
+        if self.len == EVENT_QUEUE_SIZE {
+            return false;
+        }
+
+        self.events[(self.head + self.len) % EVENT_QUEUE_SIZE] = Some(event);
+        self.len += 1;
+
+        true
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        let event = self.events[self.head].take()?;
+        self.head = (self.head + 1) % EVENT_QUEUE_SIZE;
+        self.len -= 1;
+
+        Some(event)
+    }
 }
 
 pub struct KeyboardEventRegistry<'a> {
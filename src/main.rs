@@ -10,27 +10,41 @@ extern crate alloc;
 
 use crate::console::Console;
 use crate::keyboard::KEYBOARD_REGISTRY;
-use crate::scheduler::RoundRobinScheduler;
+use crate::scheduler::SchedulerKind;
 use crate::terminal::Terminal;
+use crate::threading::PanicMessage;
 use alloc::boxed::Box;
 use bmos_shell::BmShell;
 use bootloader::{entry_point, BootInfo};
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::time::Duration;
 use graphics::{Framebuffer, GraphicsSettings};
 use psf::Font;
 use spin::Mutex;
+use x86_64::PhysAddr;
 
+mod acpi;
+mod apic;
 mod console;
 mod cpu;
+mod fpu;
+mod fw_cfg;
 mod gdt;
 mod graphics;
 mod interrupts;
 mod keyboard;
 mod memory;
+mod percpu;
 mod scheduler;
+mod selftest;
 mod serial;
+mod smp;
+mod sync;
+mod syscall;
 mod terminal;
 mod threading;
+mod timer;
 
 const FONT: &'static [u8] = include_bytes!("../font.psf");
 
@@ -41,32 +55,37 @@ static mut FRAMEBUFFER: Option<Mutex<Framebuffer>> = None;
 static mut BASE_FONT: Option<Font> = None;
 pub static mut CONSOLE: Option<Console<'static>> = None;
 pub static mut TERMINAL: Option<Terminal<'static>> = None;
-pub static mut SCHEDULER: Option<RoundRobinScheduler> = None;
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    percpu::init_bsp();
     gdt::init();
+    fpu::init();
     memory::init(
         &boot_info.memory_regions,
         boot_info.physical_memory_offset.into_option().unwrap(),
     );
 
-    unsafe {
-        let initial_task = threading::build("main", || {
-            debug!("Hello from main thread");
-        });
-        SCHEDULER = Some(RoundRobinScheduler::new(initial_task));
-    }
+    let initial_task = threading::build_boot();
+    scheduler::init(scheduler_kind(), initial_task);
+    threading::start_reaper();
 
     interrupts::init();
+    smp::init(boot_info.rsdp_addr.into_option().map(PhysAddr::new));
     keyboard::init();
+    if selftests_enabled() {
+        selftest::run();
+    }
 
     threading::spawn("test", || {
         debug!("Printing from a nice thread!");
 
-        unsafe {
-            loop {
-                asm!("hlt");
-            }
+        loop {
+            threading::sleep(Duration::from_secs(10));
+            debug!(
+                "Still alive after {} ticks, CPUs idle {}% of the time",
+                timer::ticks(),
+                scheduler::idle_ticks() * 100 / scheduler::ticks()
+            );
         }
     });
 
@@ -112,13 +131,43 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         TERMINAL.as_mut().unwrap().launch_shell(shell);
     };
 
-    loop {
-        x86_64::instructions::hlt();
-    }
+    // Everything we need from the boot info is set up now.
+    memory::reclaim_bootloader_memory();
+
+    // From here on, the other threads and the idle thread take over.
+    threading::exit();
+}
+
+/// Picks the scheduler passed with `-fw_cfg name=opt/bmos/scheduler,string=<name>`, see
+/// `SchedulerKind::from_name`. Defaults to the feedback scheduler.
+fn scheduler_kind() -> SchedulerKind {
+    let name = match fw_cfg::read_string("opt/bmos/scheduler") {
+        Some(name) => name,
+        None => return SchedulerKind::Feedback,
+    };
+
+    SchedulerKind::from_name(name.trim()).unwrap_or_else(|| {
+        debug!("Unknown scheduler '{}', using the default one", name);
+        SchedulerKind::Feedback
+    })
+}
+
+/// Whether to run the self-tests at boot. They take a while, so only the `opt/bmos/selftest`
+/// entry turns them on.
+fn selftests_enabled() -> bool {
+    fw_cfg::read_string("opt/bmos/selftest").map_or(false, |value| value.trim() == "1")
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     debug!("{}", info);
+
+    // The message is cut off if it doesn't fit, it only has to be good enough for the joiner.
+    let mut message = PanicMessage::new();
+    let _ = write!(message, "{}", info);
+
+    // Only returns if the panic didn't happen in a thread that can be joined.
+    threading::handle_panic(message);
+
     loop {}
 }
@@ -1,41 +1,91 @@
 use crate::debug;
-use bootloader::boot_info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
-use linked_list_allocator::LockedHeap;
+use crate::smp;
+use crate::sync::{SpinLock, SpinLockGuard};
+use alloc::sync::Arc;
+use bootloader::boot_info::{MemoryRegion, MemoryRegions};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::Mapper;
+use x86_64::structures::paging::page::{Page, PageRange, Size4KiB};
 use x86_64::structures::paging::page_table::PageTableFlags;
-use x86_64::structures::paging::FrameAllocator;
-use x86_64::structures::paging::{
-    page::{Page, Size4KiB},
-    PhysFrame,
-};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
 use x86_64::{
-    registers::control::Cr3,
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    registers::model_specific::{Efer, EferFlags},
     structures::paging::{OffsetPageTable, PageTable},
-    VirtAddr,
+    PhysAddr, VirtAddr,
+};
+
+pub use address_space::{
+    AddressSpace, AddressSpaceError, PageFaultError, Region, USER_SPACE_END, USER_SPACE_START,
 };
-use x86_64::{structures::paging::mapper::Mapper, PhysAddr};
+pub use frame_allocator::FrameStats;
+use frame_allocator::PhysicalFrameAllocator;
+pub use heap::HeapStats;
+use heap::KernelHeap;
+pub use range_allocator::RangeStats;
+use range_allocator::VirtualRangeAllocator;
+use slab::SlabAllocator;
+pub use slab::{SlabStats, SIZE_CLASS_COUNT};
+
+mod address_space;
+mod frame_allocator;
+mod heap;
+mod range_allocator;
+mod reclaim;
+pub mod selftest;
+mod slab;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+static HEAP: KernelHeap = KernelHeap::empty();
+static SLAB: SlabAllocator = SlabAllocator::new(SLAB_POOL_START, SLAB_POOL_SIZE);
+
+// The windows below and the heap have to stay in the first 512 GiB, the level 4 entry the kernel
+// image lives in, since everything behind it up to the upper half is private to address spaces.
+
+/// Window for page allocations that don't go through the global allocator.
+const KERNEL_PAGES_START: u64 = 0x0000_0040_0000_0000;
+const KERNEL_PAGES_SIZE: u64 = 64 * 1024 * 1024 * 1024;
 
-const KALLOC_POOL_START: u64 = 0x0000_CAFE_0000;
-const KALLOC_POOL_SIZE: u64 = 1024 * 1024;
+/// Window for kernel stacks. Each stack comes with an unmapped guard page below it.
+const KERNEL_STACKS_START: u64 = 0x0000_0050_0000_0000;
+const KERNEL_STACKS_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+const SLAB_POOL_START: u64 = 0x0000_0020_0000_0000;
+const SLAB_POOL_SIZE: u64 = 1024 * 1024 * 1024;
 
 static HEAP_START: u64 = 0x_0000_1337_1337;
-static HEAP_SIZE: u64 = 8192 * 1024; // 8 Megabytes of heap memory for the kernel
+static HEAP_INITIAL_SIZE: usize = 1024 * 1024; // the heap starts with 1 Megabyte and grows on demand
+static HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // but never beyond 256 Megabytes
 
-static mut MEMORY_MANAGER: Option<MemoryManager<'static>> = None;
+/// Every CPU allocates memory, so the memory manager is locked. The physical memory offset is
+/// needed while it is locked, so it is kept separately.
+static MEMORY_MANAGER: SpinLock<Option<MemoryManager<'static>>> = SpinLock::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static mut KERNEL_ADDRESS_SPACE: Option<Arc<AddressSpace>> = None;
 
-pub fn init(memory: &'static MemoryRegions, memory_offset: u64) {
-    let usable_memory = (&**memory)
-        .iter()
-        .find(|region| region.kind == MemoryRegionKind::Usable)
-        .unwrap();
+/// Application processors start in real mode, so their startup code has to live below 1 MiB.
+/// Low frames are the first ones the frame allocator hands out, so one is set aside right away.
+static mut AP_STARTUP_FRAME: Option<PhysFrame> = None;
 
-    let mut frame_alloc = PhysicalFrameAllocator::new(usable_memory);
+pub fn init(memory: &'static MemoryRegions, memory_offset: u64) {
+    assert!(
+        !(USER_SPACE_START..USER_SPACE_END).contains(&memory_offset),
+        "The physical memory mapping lies in the private part of the address space"
+    );
+    let mut frame_alloc = unsafe { PhysicalFrameAllocator::new(memory, memory_offset) };
+    unsafe {
+        AP_STARTUP_FRAME = frame_alloc.allocate_frame_below(PhysAddr::new(0x10_0000));
+    }
 
     debug!(
-        "Usable memory region being used for kernel heap: {:?}",
-        &usable_memory
+        "Physical frame allocator manages {} frames ({} KiB), {} free",
+        frame_alloc.total_frames(),
+        frame_alloc.total_frames() * 4,
+        frame_alloc.free_frames(),
     );
 
     debug!("Setting up page table...");
@@ -47,6 +97,8 @@ pub fn init(memory: &'static MemoryRegions, memory_offset: u64) {
         OffsetPageTable::new(l4_page_table, VirtAddr::new(memory_offset))
     };
 
+    protect_kernel_image(&mut page_table);
+
     debug!("Protecting 0x0000 to make sure we fail on nullptrs");
     let zero_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0x0));
     unsafe {
@@ -65,55 +117,234 @@ pub fn init(memory: &'static MemoryRegions, memory_offset: u64) {
         }
     }
 
-    let heap_start_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(HEAP_START));
-    let heap_end_page: Page<Size4KiB> =
-        Page::containing_address(VirtAddr::new(HEAP_START + HEAP_SIZE - 1));
+    PHYSICAL_MEMORY_OFFSET.store(memory_offset, Ordering::Relaxed);
+    *MEMORY_MANAGER.lock() = Some(MemoryManager {
+        frame_alloc,
+        page_table,
+        physical_memory_offset: memory_offset,
+        memory_regions: &memory[..],
+        kernel_pages: VirtualRangeAllocator::new(KERNEL_PAGES_START, KERNEL_PAGES_SIZE),
+        kernel_stacks: VirtualRangeAllocator::new(KERNEL_STACKS_START, KERNEL_STACKS_SIZE),
+    });
 
-    let heap_range = Page::range_inclusive(heap_start_page, heap_end_page);
+    debug!("Initializing global allocator.");
+    unsafe {
+        HEAP.init(HEAP_START, HEAP_INITIAL_SIZE, HEAP_MAX_SIZE);
+    }
+    debug!("Heap mapped successfully.");
 
     unsafe {
-        heap_range.for_each(|page| {
-            let phys_frame = match frame_alloc.allocate_frame() {
-                Some(frame) => frame,
-                None => panic!("Out of memory, no physical frames left."),
-            };
+        KERNEL_ADDRESS_SPACE = Some(Arc::new(AddressSpace::from_current()));
+    }
+}
 
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+extern "C" {
+    // Defined in link.ld, all of them are page aligned.
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+}
 
-            match page_table.map_to(page, phys_frame, flags, &mut frame_alloc) {
-                Ok(tlb) => tlb.flush(),
-                Err(error) => panic!("Failed to map heap page: {:?}", error),
-            }
-        });
+/// The sections of the kernel image, as laid out by link.ld.
+fn kernel_sections() -> [(&'static str, u64, u64); 4] {
+    unsafe {
+        [
+            (
+                ".text",
+                section_addr(&__text_start),
+                section_addr(&__text_end),
+            ),
+            (
+                ".rodata",
+                section_addr(&__rodata_start),
+                section_addr(&__rodata_end),
+            ),
+            (
+                ".data",
+                section_addr(&__data_start),
+                section_addr(&__data_end),
+            ),
+            (".bss", section_addr(&__bss_start), section_addr(&__bss_end)),
+        ]
     }
+}
 
-    debug!("Heap mapped successfully.");
-    debug!("Initializing global allocator.");
+fn section_addr(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64
+}
 
-    let heap_start = HEAP_START as usize;
-    let heap_end = HEAP_START + HEAP_SIZE - 1;
-    let heap_size = HEAP_SIZE as usize;
+/// Enforces W^X on the kernel image: code is read-only and executable, everything else is
+/// never executable and only writable if it's not `.rodata`.
+fn protect_kernel_image(page_table: &mut OffsetPageTable) {
+    // Without NXE, the NO_EXECUTE bit is reserved, and without WP, the kernel may write to
+    // read-only pages.
     unsafe {
-        ALLOCATOR.lock().init(heap_start, heap_size);
-        MEMORY_MANAGER = Some(MemoryManager {
-            frame_alloc,
-            page_table,
-            kernel_pages_allocated: 0,
-        });
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    for (name, start, end) in kernel_sections().iter() {
+        let flags = match *name {
+            ".text" => PageTableFlags::PRESENT,
+            ".rodata" => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            _ => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        };
+
+        let pages: PageRange = Page::range(
+            Page::containing_address(VirtAddr::new(*start)),
+            Page::containing_address(VirtAddr::new(*end)),
+        );
+        for page in pages {
+            unsafe {
+                match page_table.update_flags(page, flags) {
+                    Ok(tlb) => tlb.flush(),
+                    Err(error) => panic!(
+                        "Failed to protect kernel {} at {:?}: {:?}",
+                        name, page, error
+                    ),
+                }
+            }
+        }
+
+        debug!(
+            "Mapped kernel {} ({:#x}..{:#x}) as {:?}",
+            name, start, end, flags
+        );
     }
 }
 
+/// Tells which section of the kernel image `addr` belongs to, if any.
+pub fn kernel_section(addr: VirtAddr) -> Option<&'static str> {
+    kernel_sections()
+        .iter()
+        .find(|(_, start, end)| (*start..*end).contains(&addr.as_u64()))
+        .map(|(name, _, _)| *name)
+}
+
 pub struct MemoryManager<'a> {
-    frame_alloc: PhysicalFrameAllocator<'a>,
+    frame_alloc: PhysicalFrameAllocator,
     page_table: OffsetPageTable<'a>,
-    kernel_pages_allocated: usize,
+    physical_memory_offset: u64,
+    memory_regions: &'static [MemoryRegion],
+    kernel_pages: VirtualRangeAllocator,
+    kernel_stacks: VirtualRangeAllocator,
+}
+
+/// A kernel stack. The page right below the stack is reserved but never mapped, so running over
+/// the end of the stack faults instead of corrupting whatever lies below.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    guard_page: Page,
+    pages: PageRange,
+}
+
+impl KernelStack {
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.pages.start.start_address()
+    }
+
+    /// The address right above the stack. Stacks grow down, so this is where they start.
+    pub fn top(&self) -> VirtAddr {
+        self.pages.end.start_address()
+    }
+
+    /// Usable size in bytes, without the guard page.
+    pub fn size(&self) -> u64 {
+        self.top() - self.bottom()
+    }
+
+    pub fn guard_page(&self) -> Page {
+        self.guard_page
+    }
 }
 
 impl<'a> MemoryManager<'a> {
-    pub fn allocate_page(&mut self) -> Option<Page> {
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    /// Allocates and maps `count` consecutive pages, the first one aligned to `align` bytes.
+    pub fn allocate_pages(&mut self, count: u64, align: u64) -> Option<PageRange> {
+        let start = self.kernel_pages.allocate(count * 4096, align)?;
+        let first = Page::containing_address(VirtAddr::new(start));
+        let pages = Page::range(first, first + count);
+
+        for (mapped, page) in pages.enumerate() {
+            if self.map_page(page).is_none() {
+                self.unmap_pages(Page::range(first, first + mapped as u64));
+                self.kernel_pages.free(start, count * 4096);
+
+                return None;
+            }
+        }
+
+        Some(pages)
+    }
+
+    pub fn free_pages(&mut self, pages: PageRange) {
+        let start = pages.start.start_address().as_u64();
+        assert!(
+            self.kernel_pages.contains(start),
+            "{:?} was not allocated with allocate_pages",
+            pages
+        );
+
+        self.unmap_pages(pages);
+        self.kernel_pages
+            .free(start, pages.end.start_address().as_u64() - start);
+    }
+
+    /// Allocates a stack of `page_count` pages, plus the guard page below it.
+    pub fn allocate_stack(&mut self, page_count: u64) -> Option<KernelStack> {
+        let size = (page_count + 1) * 4096;
+        let start = self.kernel_stacks.allocate(size, 4096)?;
+        let guard_page = Page::containing_address(VirtAddr::new(start));
+        let stack = KernelStack {
+            guard_page,
+            pages: Page::range(guard_page + 1, guard_page + 1 + page_count),
+        };
+
+        for (mapped, page) in stack.pages.enumerate() {
+            if self.map_page(page).is_none() {
+                self.unmap_pages(Page::range(
+                    stack.pages.start,
+                    stack.pages.start + mapped as u64,
+                ));
+                self.kernel_stacks.free(start, size);
+
+                return None;
+            }
+        }
+
+        Some(stack)
+    }
+
+    pub fn free_stack(&mut self, stack: KernelStack) {
+        self.unmap_pages(stack.pages);
+
+        let start = stack.guard_page.start_address().as_u64();
+        self.kernel_stacks
+            .free(start, stack.pages.end.start_address().as_u64() - start);
+    }
+
+    /// Every stack allocation starts with its guard page, so in the stack window, an unmapped page
+    /// right below a mapped one is a guard page.
+    pub fn is_guard_page(&self, addr: VirtAddr) -> bool {
+        if !self.kernel_stacks.contains(addr.as_u64()) {
+            return false;
+        }
+
+        let page: Page = Page::containing_address(addr);
+        self.page_table.translate_page(page).is_err()
+            && self.page_table.translate_page(page + 1).is_ok()
+    }
+
+    /// Backs `page` with a fresh frame. Returns `None` if there are no frames left.
+    pub fn map_page(&mut self, page: Page) -> Option<()> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         let memory_frame = self.frame_alloc.allocate_frame()?;
-        let page = self.find_next_kalloc_page()?;
 
         unsafe {
             match self
@@ -121,56 +352,310 @@ impl<'a> MemoryManager<'a> {
                 .map_to(page, memory_frame, flags, &mut self.frame_alloc)
             {
                 Ok(tlb) => tlb.flush(),
-                Err(error) => panic!("Failed to map heap page: {:?}", error),
+                Err(error) => panic!("Failed to map kernel page: {:?}", error),
             }
         };
 
-        self.kernel_pages_allocated += 1; // we allocate only one page, but we increment by two to make sure we have guard pages inbetween
+        Some(())
+    }
+
+    /// Maps the device memory in `frame` to a fresh kernel page, with caching disabled.
+    pub fn map_device_memory(&mut self, frame: PhysFrame) -> Option<Page> {
+        let start = self.kernel_pages.allocate(4096, 4096)?;
+        let page = Page::containing_address(VirtAddr::new(start));
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::NO_EXECUTE;
+
+        unsafe {
+            match self
+                .page_table
+                .map_to(page, frame, flags, &mut self.frame_alloc)
+            {
+                Ok(tlb) => tlb.flush(),
+                Err(error) => panic!("Failed to map device memory: {:?}", error),
+            }
+        }
 
         Some(page)
     }
 
-    fn find_next_kalloc_page(&self) -> Option<Page> {
-        let start_addr = Page::containing_address(VirtAddr::new(KALLOC_POOL_START));
-        let end_addr =
-            Page::containing_address(VirtAddr::new(KALLOC_POOL_START + KALLOC_POOL_SIZE - 1));
+    /// Maps `frame` to the page with the same address, for code that runs while paging is being
+    /// turned on.
+    pub fn identity_map(&mut self, frame: PhysFrame, flags: PageTableFlags) {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        unsafe {
+            match self
+                .page_table
+                .map_to(page, frame, flags, &mut self.frame_alloc)
+            {
+                Ok(tlb) => tlb.flush(),
+                Err(error) => panic!("Failed to identity map {:?}: {:?}", frame, error),
+            }
+        }
+    }
+
+    /// Removes a mapping made with `identity_map`. The frame is left alone.
+    pub fn remove_identity_mapping(&mut self, frame: PhysFrame) {
+        let page: Page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        match self.page_table.unmap(page) {
+            Ok((_, tlb)) => tlb.flush(),
+            Err(error) => panic!("Failed to remove identity mapping: {:?}", error),
+        }
+        smp::tlb_shootdown(smp::ALL_CPUS);
+    }
+
+    /// Unmaps `pages` and frees their frames, with a single TLB shootdown for all of them.
+    fn unmap_pages(&mut self, pages: PageRange) {
+        for page in pages {
+            let frame = match self.page_table.unmap(page) {
+                Ok((frame, tlb)) => {
+                    tlb.flush();
+                    frame
+                }
+                Err(error) => panic!("Failed to unmap kernel page: {:?}", error),
+            };
 
-        let range = Page::range_inclusive(start_addr, end_addr);
+            // Other CPUs may still have the page cached, but nobody can allocate the frame again
+            // before we let go of the memory manager, which is after the shootdown.
+            unsafe {
+                self.frame_alloc.deallocate_frame(frame);
+            }
+        }
 
-        range.skip(self.kernel_pages_allocated).next()
+        smp::tlb_shootdown(smp::ALL_CPUS);
     }
 }
 
 pub fn allocate_kernel_page() -> Option<Page> {
-    unsafe { MEMORY_MANAGER.as_mut().unwrap().allocate_page() }
+    allocate_kernel_pages(1, 4096).map(|pages| pages.start)
 }
 
-struct PhysicalFrameAllocator<'a> {
-    usable_region: &'a MemoryRegion,
-    last_frame: usize,
+/// Unmaps a page obtained from `allocate_kernel_page` and returns its frame to the frame allocator.
+pub fn free_kernel_page(page: Page) {
+    free_kernel_pages(Page::range(page, page + 1))
 }
 
-impl<'a> PhysicalFrameAllocator<'a> {
-    pub fn new(usable_region: &'a MemoryRegion) -> Self {
-        Self {
-            usable_region,
-            last_frame: 0,
-        }
+/// Allocates `count` mapped, virtually contiguous pages. The first one is aligned to `align`
+/// bytes, which has to be a power of two and a multiple of the page size.
+pub fn allocate_kernel_pages(count: u64, align: u64) -> Option<PageRange> {
+    with_manager(|manager| manager.allocate_pages(count, align))
+}
+
+/// Unmaps pages obtained from `allocate_kernel_pages` and makes their addresses available again.
+pub fn free_kernel_pages(pages: PageRange) {
+    with_manager(|manager| manager.free_pages(pages))
+}
+
+/// Allocates a stack that can hold at least `size` bytes.
+pub fn allocate_kernel_stack(size: usize) -> Option<KernelStack> {
+    let page_count = (size as u64 + 4095) / 4096;
+    with_manager(|manager| manager.allocate_stack(page_count))
+}
+
+pub fn free_kernel_stack(stack: KernelStack) {
+    with_manager(|manager| manager.free_stack(stack))
+}
+
+/// Tells whether `addr` lies in the guard page of any kernel stack, or `None` if it lies in the
+/// stack window and the memory manager is locked. Fault handlers call this, and the fault may
+/// have hit while this CPU held the lock.
+pub fn is_guard_page(addr: VirtAddr) -> Option<bool> {
+    let window = KERNEL_STACKS_START..KERNEL_STACKS_START + KERNEL_STACKS_SIZE;
+    if !window.contains(&addr.as_u64()) {
+        return Some(false);
     }
+
+    let manager = MEMORY_MANAGER.try_lock()?;
+    Some(
+        manager
+            .as_ref()
+            .map_or(false, |manager| manager.is_guard_page(addr)),
+    )
+}
+
+/// Tells whether all of `start..start + size` lies in mapped slab or heap pages, which is where
+/// the kernel allocator hands out memory.
+pub fn is_heap_memory(start: VirtAddr, size: u64) -> bool {
+    let end = match start.as_u64().checked_add(size) {
+        Some(end) if size > 0 => end,
+        _ => return false,
+    };
+    let in_window = |window_start: u64, window_size: u64| {
+        start.as_u64() >= window_start && end <= window_start + window_size
+    };
+    if !in_window(SLAB_POOL_START, SLAB_POOL_SIZE) && !in_window(HEAP_START, HEAP_MAX_SIZE as u64) {
+        return false;
+    }
+
+    let mut pages = Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(start),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    with_manager(|manager| pages.all(|page| manager.page_table.translate_page(page).is_ok()))
 }
 
-unsafe impl<'a> FrameAllocator<Size4KiB> for PhysicalFrameAllocator<'a> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let mut phys_iter = (self.usable_region.start..self.usable_region.end)
-            .step_by(4096)
-            .skip(self.last_frame);
-        let frame_start = phys_iter.next();
+/// Makes the device registers at `addr` accessible and returns their virtual address.
+pub fn map_device_memory(addr: PhysAddr) -> Option<VirtAddr> {
+    let frame = PhysFrame::containing_address(addr);
+    let page = with_manager(|manager| manager.map_device_memory(frame))?;
+
+    Some(page.start_address() + (addr - frame.start_address()))
+}
+
+/// The frame below 1 MiB set aside for the application processors' startup code.
+pub fn ap_startup_frame() -> Option<PhysFrame> {
+    unsafe { AP_STARTUP_FRAME }
+}
+
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags) {
+    with_manager(|manager| manager.identity_map(frame, flags))
+}
+
+pub fn remove_identity_mapping(frame: PhysFrame) {
+    with_manager(|manager| manager.remove_identity_mapping(frame))
+}
+
+/// Gives access to physical memory through the mapping of all of it.
+pub fn physical_to_virtual(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + physical_memory_offset())
+}
 
-        self.last_frame += 1;
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+    pub slabs: [SlabStats; SIZE_CLASS_COUNT],
+    pub kernel_pages: RangeStats,
+    pub kernel_stacks: RangeStats,
+}
+
+/// Collects the usage of every memory allocator in the kernel.
+pub fn stats() -> MemoryStats {
+    let (frames, kernel_pages, kernel_stacks) = with_manager(|manager| {
+        (
+            manager.frame_alloc.stats(),
+            manager.kernel_pages.stats(),
+            manager.kernel_stacks.stats(),
+        )
+    });
+
+    MemoryStats {
+        frames,
+        heap: HEAP.stats(),
+        slabs: SLAB.stats(),
+        kernel_pages,
+        kernel_stacks,
+    }
+}
+
+/// Gives the memory the bootloader used for itself to the frame allocator, once the kernel no
+/// longer needs the boot info. Only call this once.
+pub fn reclaim_bootloader_memory() {
+    let (l4_frame, _) = Cr3::read();
+    assert_eq!(
+        l4_frame,
+        kernel_address_space().l4_frame(),
+        "Bootloader memory must be reclaimed in the kernel address space"
+    );
+
+    // Threads are preemptible by now. Whatever runs in between must not see the frames half
+    // reclaimed, or change the page tables we just walked.
+    let frames = without_interrupts(|| {
+        let frames = unsafe {
+            reclaim::unused_bootloader_frames(memory_map(), l4_frame, physical_memory_offset())
+        };
+        with_manager(|manager| {
+            for frame in frames.iter() {
+                unsafe { manager.frame_alloc.reclaim(*frame) };
+            }
+        });
+
+        frames
+    });
+
+    debug!(
+        "Reclaimed {} KiB of bootloader memory",
+        frames.len() * 4096 / 1024
+    );
+}
+
+/// The physical memory map the bootloader handed to us.
+pub fn memory_map() -> &'static [MemoryRegion] {
+    with_manager(|manager| manager.memory_regions)
+}
+
+/// The address space every kernel thread runs in.
+pub fn kernel_address_space() -> Arc<AddressSpace> {
+    unsafe { KERNEL_ADDRESS_SPACE.clone().unwrap() }
+}
+
+/// The address space that is currently loaded into CR3.
+pub fn current_address_space() -> Arc<AddressSpace> {
+    address_space::current()
+}
+
+/// Runs `f` with the memory manager locked. `f` must not allocate from the heap, which might
+/// have to lock the memory manager to grow.
+fn with_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager<'static>) -> R,
+{
+    f(MEMORY_MANAGER
+        .lock()
+        .as_mut()
+        .expect("The memory manager isn't initialized"))
+}
+
+/// The frame allocator, which keeps the memory manager locked until it is dropped.
+fn frame_allocator() -> FrameAllocatorGuard {
+    FrameAllocatorGuard(MEMORY_MANAGER.lock())
+}
+
+struct FrameAllocatorGuard(SpinLockGuard<'static, Option<MemoryManager<'static>>>);
+
+impl Deref for FrameAllocatorGuard {
+    type Target = PhysicalFrameAllocator;
+
+    fn deref(&self) -> &PhysicalFrameAllocator {
+        &self.0.as_ref().unwrap().frame_alloc
+    }
+}
+
+impl DerefMut for FrameAllocatorGuard {
+    fn deref_mut(&mut self) -> &mut PhysicalFrameAllocator {
+        &mut self.0.as_mut().unwrap().frame_alloc
+    }
+}
+
+fn physical_memory_offset() -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
+fn map_kernel_page(page: Page) -> Option<()> {
+    with_manager(|manager| manager.map_page(page))
+}
+
+/// Small allocations are served by the slab allocator, everything else by the kernel heap.
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    // The slab and heap locks are `SpinLock`s, so nobody is preempted while holding one, and
+    // whoever spins for one still answers TLB shootdowns.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match SLAB.allocate(layout) {
+            Some(ptr) => ptr,
+            None => HEAP.alloc(layout),
+        }
+    }
 
-        match frame_start {
-            Some(addr) => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
-            None => None,
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Route by address, small allocations end up on the heap when the slabs run out of pages.
+        if SLAB.contains(ptr) {
+            SLAB.deallocate(ptr, layout);
+        } else {
+            HEAP.dealloc(ptr, layout);
         }
     }
 }
@@ -0,0 +1,757 @@
+use crate::debug;
+use crate::percpu;
+use crate::smp;
+use crate::smp::MAX_CPUS;
+use crate::sync::SpinLock;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::Mapper;
+use x86_64::structures::paging::page_table::{PageTableEntry, PageTableFlags, PageTableIndex};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageTable, PhysFrame,
+};
+use x86_64::VirtAddr;
+
+/// Start of the part of every address space that is private to it.
+pub const USER_SPACE_START: u64 = 0x0000_0080_0000_0000;
+/// End (exclusive) of the private part of every address space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// The level 4 entries covering `USER_SPACE_START..USER_SPACE_END`, the whole lower half but the
+/// first 512 GiB. All other entries belong to the kernel and are shared.
+///
+/// Entry 0 stays with the kernel because the kernel is linked at 2 MiB, which the default code
+/// model needs, and the bootloader identity-maps the frames it switches to the kernel from. The
+/// kernel's memory pools sit next to the image there. The bootloader's own mappings, like the
+/// physical memory and the boot stack, are pinned to the upper half in `Cargo.toml`.
+const USER_L4_ENTRIES: Range<usize> = 1..256;
+
+/// Flags for page tables in the private part. Whether a page is writable is decided by its own
+/// entry alone, so copy-on-write pages can become writable without touching their tables.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+/// Marks a read-only page that is shared with another address space and gets copied on the
+/// first write. Bit 9 is ignored by the CPU and free for the OS to use.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The address space every CPU most recently activated.
+static mut CURRENT: [Option<Arc<AddressSpace>>; MAX_CPUS] = [NONE; MAX_CPUS];
+const NONE: Option<Arc<AddressSpace>> = None;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    OutsideUserSpace,
+    Unaligned,
+    Overlapping,
+    /// Part of the range isn't covered by any region.
+    NotReserved,
+    OutOfMemory,
+}
+
+/// Why `handle_page_fault` couldn't resolve a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// The address isn't reserved, or its region doesn't allow the access.
+    AccessViolation,
+    /// No frame was left to back or copy the page.
+    OutOfMemory,
+}
+
+/// A reserved range of virtual memory. Pages in it are only backed by frames once they are touched.
+/// `flags` are the flags its pages get mapped with. Without `PRESENT`, any access faults.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Tells whether the access described by `error_code` is allowed in this region.
+    fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        if !self.flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !self.flags.contains(PageTableFlags::WRITABLE)
+        {
+            return false;
+        }
+
+        !(error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && self.flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+/// A set of page tables. The kernel's level 4 entries are shared between all address spaces,
+/// so kernel code and data look the same everywhere, while `USER_SPACE_START..USER_SPACE_END`
+/// is private to each address space.
+///
+/// Kernel mappings only show up in every address space if they live below a level 4 entry that
+/// was present when the address space was created. All kernel memory pools sit below entry 0,
+/// the bootloader's mappings below entries 256 to 259.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+    /// The address space the bootloader built for us. It's never torn down.
+    is_kernel: bool,
+    /// Reserved parts of the private address range, sorted by start address.
+    regions: SpinLock<Vec<Region>>,
+    /// The CPUs that have it loaded, see `activate`.
+    active_cpus: AtomicU64,
+    /// Held while the private part of the page tables changes. Page faults of several CPUs may
+    /// want to back or copy the same page at once.
+    page_tables: SpinLock<()>,
+}
+
+impl AddressSpace {
+    /// Wraps the currently active page tables.
+    pub(super) fn from_current() -> Self {
+        let (l4_frame, _) = Cr3::read();
+
+        Self {
+            l4_frame,
+            is_kernel: true,
+            regions: SpinLock::new(Vec::new()),
+            active_cpus: AtomicU64::new(0),
+            page_tables: SpinLock::new(()),
+        }
+    }
+
+    /// Creates an address space that shares the kernel's mappings and has an empty private part.
+    pub fn new() -> Option<Self> {
+        let l4_frame = super::frame_allocator().allocate_frame()?;
+        let l4_table = unsafe { &mut *table_ptr(l4_frame) };
+        l4_table.zero();
+
+        let kernel_l4_table = unsafe { &*table_ptr(super::kernel_address_space().l4_frame) };
+        for index in (0..512).filter(|index| !USER_L4_ENTRIES.contains(index)) {
+            l4_table[index] = kernel_l4_table[index].clone();
+        }
+
+        debug!("Created address space with level 4 table at {:?}", l4_frame);
+
+        Some(Self {
+            l4_frame,
+            is_kernel: false,
+            regions: SpinLock::new(Vec::new()),
+            active_cpus: AtomicU64::new(0),
+            page_tables: SpinLock::new(()),
+        })
+    }
+
+    /// Reserves `size` bytes at `start`. Nothing is mapped yet, pages are backed with zeroed frames
+    /// as soon as they are accessed.
+    pub fn reserve(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let region = Region {
+            start,
+            end: check_range(start, size)?,
+            flags,
+        };
+
+        let mut regions = self.regions.lock();
+        if regions
+            .iter()
+            .any(|other| other.start < region.end && region.start < other.end)
+        {
+            return Err(AddressSpaceError::Overlapping);
+        }
+
+        let index = regions
+            .iter()
+            .position(|other| other.start > region.start)
+            .unwrap_or_else(|| regions.len());
+        regions.insert(index, region);
+
+        Ok(())
+    }
+
+    /// Reserves `size` bytes at the lowest address where they fit and returns that address.
+    pub fn reserve_anywhere(
+        &self,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        check_range(VirtAddr::new(USER_SPACE_START), size)?;
+
+        let mut start = VirtAddr::new(USER_SPACE_START);
+        for region in self.regions.lock().iter() {
+            if region.start - start >= size {
+                break;
+            }
+            start = region.end;
+        }
+
+        self.reserve(start, size, flags)?;
+
+        Ok(start)
+    }
+
+    /// Backs all pages in `start..start + size` right away instead of on first access.
+    /// Pages of inaccessible regions are left alone.
+    pub fn populate(&self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        let _page_tables = self.page_tables.lock();
+        let regions = self.regions.lock().clone();
+        let covering = covering_regions(&regions, start, end)?;
+
+        for region in &regions[covering] {
+            if !region.flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            let first = core::cmp::max(region.start, start);
+            let last = core::cmp::min(region.end, end) - 1u64;
+            for page in Page::range_inclusive(
+                Page::containing_address(first),
+                Page::containing_address(last),
+            ) {
+                if self.map(page, region.flags).is_none() {
+                    return Err(AddressSpaceError::OutOfMemory);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the flags of all pages in `start..start + size`, including the ones that are
+    /// already mapped. Copy-on-write pages stay read-only until they are copied.
+    pub fn protect(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        let _page_tables = self.page_tables.lock();
+        let mut regions = self.regions.lock();
+        let changed = split_regions(&mut regions, start, end)?;
+        for region in &mut regions[changed] {
+            region.flags = flags;
+        }
+        drop(regions);
+
+        let page_flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        for page in Page::range(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        ) {
+            let entry = match unsafe { self.leaf_entry(page) } {
+                Some(entry) if !entry.is_unused() => entry,
+                _ => continue,
+            };
+
+            let mut new_flags = page_flags;
+            if entry.flags().contains(COPY_ON_WRITE) {
+                new_flags = (new_flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            // Inaccessible pages keep their frame, so it survives until the next `protect`.
+            entry.set_addr(entry.addr(), new_flags);
+        }
+        self.tlb_shootdown();
+
+        Ok(())
+    }
+
+    /// Drops the reservation of `start..start + size` and frees all pages mapped in it.
+    pub fn release(&self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let end = check_range(start, size)?;
+        let _page_tables = self.page_tables.lock();
+        let mut regions = self.regions.lock();
+        let released = split_regions(&mut regions, start, end)?;
+        regions.drain(released);
+        drop(regions);
+
+        let frames = Page::range(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        )
+        .filter_map(|page| self.clear_entry(page))
+        .collect::<Vec<_>>();
+        self.tlb_shootdown();
+
+        let mut frame_alloc = super::frame_allocator();
+        for frame in frames {
+            unsafe { frame_alloc.deallocate_frame(frame) };
+        }
+
+        Ok(())
+    }
+
+    /// Whether all of `start..start + size` lies in reserved regions that allow writing.
+    pub fn is_writable(&self, start: VirtAddr, size: u64) -> bool {
+        let end = match start.as_u64().checked_add(size) {
+            Some(end) if start.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END => {
+                VirtAddr::new(end)
+            }
+            _ => return false,
+        };
+
+        let regions = self.regions.lock();
+        covering_regions(&regions, start, end).map_or(false, |covering| {
+            regions[covering].iter().all(|region| {
+                region
+                    .flags
+                    .contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            })
+        })
+    }
+
+    pub fn find_region(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions
+            .lock()
+            .iter()
+            .find(|region| region.contains(addr))
+            .copied()
+    }
+
+    /// Backs the page at `addr` if it belongs to a reserved region and the access is allowed,
+    /// or gives the address space its own copy of a copy-on-write page on the first write.
+    pub fn handle_page_fault(
+        &self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), PageFaultError> {
+        // Looked up under the lock, so the region can't be released before the page is backed.
+        let _page_tables = self.page_tables.lock();
+        let region = match self.find_region(addr) {
+            Some(region) if region.permits(error_code) => region,
+            _ => return Err(PageFaultError::AccessViolation),
+        };
+
+        let page = Page::containing_address(addr);
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                return Err(PageFaultError::AccessViolation);
+            }
+            return self.copy_on_write(page);
+        }
+
+        self.map(page, region.flags)
+            .ok_or(PageFaultError::OutOfMemory)
+    }
+
+    /// Backs `page` in the private part of this address space with a fresh, zeroed frame, unless
+    /// another fault got to it first. Needs `page_tables` to be locked.
+    fn map(&self, page: Page, flags: PageTableFlags) -> Option<()> {
+        assert_user_page(page);
+
+        let mapped = unsafe { self.leaf_entry(page) }.map_or(false, |entry| !entry.is_unused());
+        if mapped {
+            return Some(());
+        }
+
+        let mut frame_alloc = super::frame_allocator();
+        let frame = frame_alloc.allocate_frame()?;
+        unsafe {
+            core::ptr::write_bytes(frame_ptr(frame), 0, 4096);
+        }
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = unsafe { self.mapper() };
+        unsafe {
+            match mapper.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                USER_TABLE_FLAGS,
+                &mut *frame_alloc,
+            ) {
+                Ok(tlb) => tlb.flush(),
+                Err(error) => panic!("Failed to map user page: {:?}", error),
+            }
+        }
+
+        Some(())
+    }
+
+    /// Clears the entry that maps `page` and returns its frame, which must not be freed before
+    /// the next TLB shootdown.
+    fn clear_entry(&self, page: Page) -> Option<PhysFrame> {
+        let entry = unsafe { self.leaf_entry(page) }?;
+        if entry.is_unused() {
+            return None;
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        Some(frame)
+    }
+
+    /// Creates a copy of this address space for fork-style process creation. Instead of copying
+    /// memory, both address spaces share every frame read-only, and whichever writes to a page
+    /// first gets its own copy.
+    pub fn fork(&self) -> Option<Self> {
+        let child = Self::new()?;
+        let _page_tables = self.page_tables.lock();
+        *child.regions.lock() = self.regions.lock().clone();
+
+        let mut frame_alloc = super::frame_allocator();
+        let mut child_mapper = unsafe { child.mapper() };
+        unsafe {
+            self.for_each_mapped_page(|page, entry| {
+                // Even read-only pages are marked, so they don't get shared writable if they
+                // are made writable later on.
+                let flags = (entry.flags() - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+
+                let frame = PhysFrame::containing_address(entry.addr());
+                frame_alloc.share_frame(frame);
+                match child_mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    USER_TABLE_FLAGS,
+                    &mut *frame_alloc,
+                ) {
+                    // The child isn't active, so there is nothing cached to flush.
+                    Ok(tlb) => tlb.ignore(),
+                    Err(error) => panic!("Failed to map page in forked address space: {:?}", error),
+                }
+            });
+        }
+        drop(frame_alloc);
+
+        // Whatever runs in this address space must not write to the shared frames anymore.
+        self.tlb_shootdown();
+
+        Some(child)
+    }
+
+    /// Gives this address space a private, writable copy of the copy-on-write `page`.
+    /// If nobody else references the frame anymore, it is simply made writable again.
+    /// Needs `page_tables` to be locked.
+    fn copy_on_write(&self, page: Page) -> Result<(), PageFaultError> {
+        let entry = match unsafe { self.leaf_entry(page) } {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+            // Another fault copied it first, and this CPU saw the old entry until it flushed.
+            Some(entry) if entry.flags().contains(PageTableFlags::WRITABLE) => return Ok(()),
+            _ => return Err(PageFaultError::AccessViolation),
+        };
+
+        let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let frame = entry.frame().unwrap();
+        let mut frame_alloc = super::frame_allocator();
+
+        if frame_alloc.ref_count(frame) == 1 {
+            entry.set_flags(flags);
+            drop(frame_alloc);
+            self.tlb_shootdown();
+            return Ok(());
+        }
+
+        let copy = frame_alloc
+            .allocate_frame()
+            .ok_or(PageFaultError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame_ptr(frame), frame_ptr(copy), 4096);
+            entry.set_frame(copy, flags);
+        }
+        drop(frame_alloc);
+
+        // Other CPUs may read the shared frame through the old entry until they flushed.
+        self.tlb_shootdown();
+        unsafe {
+            super::frame_allocator().deallocate_frame(frame);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with every mapped page in the private part and the entry that maps it.
+    /// This includes inaccessible pages, whose entries aren't present but still hold a frame.
+    unsafe fn for_each_mapped_page<F>(&self, mut f: F)
+    where
+        F: FnMut(Page, &mut PageTableEntry),
+    {
+        let l4_table = &mut *table_ptr(self.l4_frame);
+        for l4_index in USER_L4_ENTRIES {
+            for_each_present(&mut l4_table[l4_index], |l3_table| {
+                for l3_index in 0..512 {
+                    for_each_present(&mut l3_table[l3_index], |l2_table| {
+                        for l2_index in 0..512 {
+                            for_each_present(&mut l2_table[l2_index], |l1_table| {
+                                for l1_index in 0..512 {
+                                    let entry = &mut l1_table[l1_index];
+                                    if entry.is_unused() {
+                                        continue;
+                                    }
+
+                                    let page = Page::from_page_table_indices(
+                                        PageTableIndex::new(l4_index as u16),
+                                        PageTableIndex::new(l3_index as u16),
+                                        PageTableIndex::new(l2_index as u16),
+                                        PageTableIndex::new(l1_index as u16),
+                                    );
+                                    f(page, entry);
+                                }
+                            });
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    /// Walks the page tables down to the entry that maps `page`, if all tables on the way exist.
+    unsafe fn leaf_entry(&self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut table = &mut *table_ptr(self.l4_frame);
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()].iter() {
+            let entry = &table[*index];
+            if !entry.flags().contains(PageTableFlags::PRESENT)
+                || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            {
+                return None;
+            }
+            table = &mut *table_ptr(entry.frame().unwrap());
+        }
+
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// Loads this address space into CR3, unless it is already active.
+    pub fn activate(self: &Arc<Self>) {
+        without_interrupts(|| {
+            let cpu = percpu::cpu_id();
+            // Before CR3 is loaded, so a shootdown that doesn't see us yet changed the page
+            // tables before we start caching them.
+            self.active_cpus.fetch_or(1 << cpu, Ordering::SeqCst);
+
+            let (active_frame, flags) = Cr3::read();
+            if active_frame != self.l4_frame {
+                unsafe {
+                    Cr3::write(self.l4_frame, flags);
+                }
+            }
+
+            let previous = unsafe { CURRENT[cpu].replace(self.clone()) };
+            // Loading CR3 dropped whatever the TLB held of the previous one.
+            if let Some(previous) = previous.filter(|previous| !Arc::ptr_eq(previous, self)) {
+                previous
+                    .active_cpus
+                    .fetch_and(!(1 << cpu), Ordering::SeqCst);
+            }
+        });
+    }
+
+    /// Makes sure no CPU that may have this address space loaded still uses what was just
+    /// changed in its page tables. The kernel's address space is loaded wherever no other one is.
+    fn tlb_shootdown(&self) {
+        if self.is_kernel {
+            smp::tlb_shootdown(smp::ALL_CPUS);
+            return;
+        }
+
+        // The page tables have to be changed before we look for CPUs, see `activate`.
+        fence(Ordering::SeqCst);
+        smp::tlb_shootdown(self.active_cpus.load(Ordering::SeqCst));
+    }
+
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4_frame
+    }
+
+    /// # Safety
+    /// Whoever uses the mapper has to make sure not to create aliasing mappings.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(
+            &mut *table_ptr(self.l4_frame),
+            VirtAddr::new(super::physical_memory_offset()),
+        )
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_kernel {
+            return;
+        }
+
+        assert!(!self.is_active(), "Tearing down the active address space");
+
+        let l4_table = unsafe { &mut *table_ptr(self.l4_frame) };
+        for index in USER_L4_ENTRIES {
+            if let Ok(frame) = l4_table[index].frame() {
+                unsafe {
+                    free_table(frame, 3);
+                }
+            }
+        }
+
+        unsafe {
+            super::frame_allocator().deallocate_frame(self.l4_frame);
+        }
+
+        debug!(
+            "Tore down address space with level 4 table at {:?}",
+            self.l4_frame
+        );
+    }
+}
+
+/// Frees the table in `frame` along with all tables and frames mapped below it.
+/// `level` is 3 for a PDPT and 1 for a page table, whose entries point to mapped frames.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = &*table_ptr(frame);
+    for index in 0..512 {
+        let entry = &table[index];
+        if level == 1 {
+            // Inaccessible pages aren't present, but still own their frame.
+            if !entry.is_unused() {
+                super::frame_allocator()
+                    .deallocate_frame(PhysFrame::containing_address(entry.addr()));
+            }
+            continue;
+        }
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        assert!(
+            !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+            "Huge pages are not supported in user space"
+        );
+
+        free_table(entry.frame().unwrap(), level - 1);
+    }
+
+    super::frame_allocator().deallocate_frame(frame);
+}
+
+/// Calls `f` with the table `entry` points to, if it is present.
+unsafe fn for_each_present<F>(entry: &mut PageTableEntry, f: F)
+where
+    F: FnOnce(&mut PageTable),
+{
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    assert!(
+        !flags.contains(PageTableFlags::HUGE_PAGE),
+        "Huge pages are not supported in user space"
+    );
+
+    f(&mut *table_ptr(entry.frame().unwrap()));
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (frame.start_address().as_u64() + super::physical_memory_offset()) as *mut u8
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    frame_ptr(frame) as *mut PageTable
+}
+
+/// Checks that `start..start + size` is a non-empty, page aligned part of user space and returns
+/// its end.
+fn check_range(start: VirtAddr, size: u64) -> Result<VirtAddr, AddressSpaceError> {
+    if !start.is_aligned(4096u64) || size % 4096 != 0 || size == 0 {
+        return Err(AddressSpaceError::Unaligned);
+    }
+
+    let end = start.as_u64().checked_add(size);
+    if start.as_u64() < USER_SPACE_START || end.map_or(true, |end| end > USER_SPACE_END) {
+        return Err(AddressSpaceError::OutsideUserSpace);
+    }
+
+    Ok(VirtAddr::new(end.unwrap()))
+}
+
+/// Returns the indices of the sorted `regions` that overlap `start..end`, making sure they cover
+/// all of it without gaps.
+fn covering_regions(
+    regions: &[Region],
+    start: VirtAddr,
+    end: VirtAddr,
+) -> Result<Range<usize>, AddressSpaceError> {
+    let first = regions
+        .iter()
+        .position(|region| region.end > start)
+        .unwrap_or_else(|| regions.len());
+    let mut covered = start;
+    let mut last = first;
+    while covered < end {
+        match regions.get(last) {
+            Some(region) if region.start <= covered => covered = region.end,
+            _ => return Err(AddressSpaceError::NotReserved),
+        }
+        last += 1;
+    }
+
+    Ok(first..last)
+}
+
+/// Splits the regions at `start` and `end`, so `start..end` is covered by whole regions, and
+/// returns their indices. Nothing is changed if part of the range isn't reserved.
+fn split_regions(
+    regions: &mut Vec<Region>,
+    start: VirtAddr,
+    end: VirtAddr,
+) -> Result<Range<usize>, AddressSpaceError> {
+    let covering = covering_regions(regions, start, end)?;
+
+    let (mut first, mut last) = (covering.start, covering.end);
+    if regions[first].start < start {
+        let tail = Region {
+            start,
+            ..regions[first]
+        };
+        regions[first].end = start;
+        regions.insert(first + 1, tail);
+        first += 1;
+        last += 1;
+    }
+    if regions[last - 1].end > end {
+        let tail = Region {
+            start: end,
+            ..regions[last - 1]
+        };
+        regions[last - 1].end = end;
+        regions.insert(last, tail);
+    }
+
+    Ok(first..last)
+}
+
+fn assert_user_page(page: Page) {
+    let addr = page.start_address().as_u64();
+    assert!(
+        (USER_SPACE_START..USER_SPACE_END).contains(&addr),
+        "{:?} is outside of the private part of the address space",
+        page
+    );
+}
+
+/// The address space the calling CPU most recently activated.
+pub fn current() -> Arc<AddressSpace> {
+    unsafe {
+        CURRENT[percpu::cpu_id()]
+            .clone()
+            .unwrap_or_else(|| super::kernel_address_space())
+    }
+}
@@ -0,0 +1,313 @@
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Enough summary levels to cover 64^5 frames, which equals 4 TiB of physical memory.
+const MAX_LEVELS: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Hands out physical frames from every usable region of the bootloader's memory map.
+/// Frames the bootloader used are managed as well, but start out allocated until they are
+/// handed over with `reclaim`.
+///
+/// The allocator keeps a bitmap with one bit per frame (set = free) between the lowest and the
+/// highest usable or bootloader address. On top of that bitmap sit summary levels, where every
+/// bit tells if the corresponding word one level below still has a free frame. Allocating and
+/// freeing only walks down or up these levels, so both are O(log n) instead of scanning the whole
+/// bitmap.
+///
+/// Frames can be shared between address spaces, so every frame also has a reference count.
+/// Deallocating a frame only drops one reference, it becomes free once the last one is gone.
+pub struct PhysicalFrameAllocator {
+    bitmap: &'static mut [u64],
+    ref_counts: &'static mut [u16],
+    level_offsets: [usize; MAX_LEVELS],
+    level_count: usize,
+    first_frame: u64,
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl PhysicalFrameAllocator {
+    /// Builds the allocator for all usable regions in `regions`.
+    ///
+    /// The bitmap and the reference counts are placed at the start of the first usable region that
+    /// is large enough to hold them and are accessed through the physical memory mapping at
+    /// `memory_offset`.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the usable regions are really unused and that all of physical
+    /// memory is mapped at `memory_offset`.
+    pub unsafe fn new(regions: &[MemoryRegion], memory_offset: u64) -> Self {
+        let regions_of_kind = |kind| {
+            regions
+                .iter()
+                .filter(move |region| region.kind == kind)
+                .map(|region| (align_up(region.start), align_down(region.end)))
+                .filter(|(start, end)| start < end)
+        };
+        let usable_regions = || regions_of_kind(MemoryRegionKind::Usable);
+        let managed_regions =
+            || usable_regions().chain(regions_of_kind(MemoryRegionKind::Bootloader));
+
+        usable_regions()
+            .next()
+            .expect("No usable memory region found.");
+        let first_frame = managed_regions()
+            .map(|(start, _)| start / FRAME_SIZE)
+            .min()
+            .unwrap();
+        let last_frame = managed_regions()
+            .map(|(_, end)| end / FRAME_SIZE)
+            .max()
+            .unwrap();
+        let frame_count = (last_frame - first_frame) as usize;
+
+        let mut level_offsets = [0; MAX_LEVELS];
+        let mut level_count = 0;
+        let mut entries = frame_count;
+        let mut total_words = 0;
+        loop {
+            assert!(
+                level_count < MAX_LEVELS,
+                "Too much physical memory for the frame allocator."
+            );
+
+            let words = (entries + BITS_PER_WORD - 1) / BITS_PER_WORD;
+            level_offsets[level_count] = total_words;
+            level_count += 1;
+            total_words += words;
+
+            if words == 1 {
+                break;
+            }
+            entries = words;
+        }
+
+        let bitmap_bytes = (total_words * 8) as u64;
+        let metadata_bytes = align_up(bitmap_bytes + (frame_count * 2) as u64);
+        let (bitmap_start, _) = usable_regions()
+            .find(|(start, end)| end - start >= metadata_bytes)
+            .expect("No usable memory region is large enough for the frame bitmap.");
+
+        let bitmap = unsafe {
+            core::slice::from_raw_parts_mut((bitmap_start + memory_offset) as *mut u64, total_words)
+        };
+        bitmap.fill(0);
+
+        let ref_counts = unsafe {
+            core::slice::from_raw_parts_mut(
+                (bitmap_start + memory_offset + bitmap_bytes) as *mut u16,
+                frame_count,
+            )
+        };
+        ref_counts.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            ref_counts,
+            level_offsets,
+            level_count,
+            first_frame,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        let bitmap_frames = bitmap_start / FRAME_SIZE..(bitmap_start + metadata_bytes) / FRAME_SIZE;
+        for (start, end) in usable_regions() {
+            for frame in start / FRAME_SIZE..end / FRAME_SIZE {
+                allocator.total_frames += 1;
+                if !bitmap_frames.contains(&frame) {
+                    allocator.mark_free((frame - first_frame) as usize);
+                }
+            }
+        }
+
+        allocator
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames(),
+            used: self.used_frames(),
+            free: self.free_frames(),
+        }
+    }
+
+    /// Adds a frame the bootloader used to the free frames.
+    ///
+    /// # Safety
+    /// `frame` has to be part of a bootloader region and must not be used anymore.
+    pub unsafe fn reclaim(&mut self, frame: PhysFrame) {
+        let index = self.index_for_frame(frame);
+        assert!(
+            self.ref_counts[index] == 0 && !self.is_free(index),
+            "{:?} can't be reclaimed",
+            frame
+        );
+
+        self.total_frames += 1;
+        self.mark_free(index);
+    }
+
+    /// Allocates the lowest free frame that ends below `limit`, for hardware that can only
+    /// address low memory. Linear in the number of frames below `limit`, so keep it small.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end_frame = core::cmp::min(
+            limit.as_u64() / FRAME_SIZE,
+            self.first_frame + self.frame_count as u64,
+        );
+        // Frame 0 holds the real mode interrupt vector table.
+        let start_frame = core::cmp::max(self.first_frame, 1);
+
+        let index = (start_frame..end_frame)
+            .map(|frame| (frame - self.first_frame) as usize)
+            .find(|&index| self.is_free(index))?;
+        self.mark_used(index);
+        self.ref_counts[index] = 1;
+
+        Some(self.frame_for_index(index))
+    }
+
+    /// Adds a reference to an allocated frame, so it is only freed once every user deallocated it.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = self.index_for_frame(frame);
+        assert!(self.ref_counts[index] > 0, "Sharing free {:?}", frame);
+
+        self.ref_counts[index] = self.ref_counts[index]
+            .checked_add(1)
+            .expect("Too many references to a single frame");
+    }
+
+    pub fn ref_count(&self, frame: PhysFrame) -> u16 {
+        self.ref_counts[self.index_for_frame(frame)]
+    }
+
+    fn word_mut(&mut self, level: usize, index: usize) -> &mut u64 {
+        let offset = self.level_offsets[level];
+        &mut self.bitmap[offset + index]
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // Walk down from the single top-level word, always following the first set bit.
+        let mut index = 0;
+        for level in (0..self.level_count).rev() {
+            let word = self.bitmap[self.level_offsets[level] + index];
+            debug_assert!(word != 0, "Frame bitmap summary is out of sync");
+            index = index * BITS_PER_WORD + word.trailing_zeros() as usize;
+        }
+
+        Some(index)
+    }
+
+    fn is_free(&self, frame_index: usize) -> bool {
+        let word = self.bitmap[self.level_offsets[0] + frame_index / BITS_PER_WORD];
+        word & (1 << (frame_index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, frame_index: usize) {
+        let mut index = frame_index;
+        for level in 0..self.level_count {
+            let word = self.word_mut(level, index / BITS_PER_WORD);
+            *word &= !(1 << (index % BITS_PER_WORD));
+            if *word != 0 {
+                break;
+            }
+            index /= BITS_PER_WORD;
+        }
+
+        self.free_frames -= 1;
+    }
+
+    fn mark_free(&mut self, frame_index: usize) {
+        let mut index = frame_index;
+        for level in 0..self.level_count {
+            let word = self.word_mut(level, index / BITS_PER_WORD);
+            let was_empty = *word == 0;
+            *word |= 1 << (index % BITS_PER_WORD);
+            if !was_empty {
+                break;
+            }
+            index /= BITS_PER_WORD;
+        }
+
+        self.free_frames += 1;
+    }
+
+    fn index_for_frame(&self, frame: PhysFrame) -> usize {
+        let frame_number = frame.start_address().as_u64() / FRAME_SIZE;
+        assert!(
+            frame_number >= self.first_frame
+                && frame_number < self.first_frame + self.frame_count as u64,
+            "{:?} is not managed by the frame allocator",
+            frame
+        );
+
+        (frame_number - self.first_frame) as usize
+    }
+
+    fn frame_for_index(&self, index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(
+            (self.first_frame + index as u64) * FRAME_SIZE,
+        ))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for PhysicalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let index = self.find_free()?;
+        debug_assert!(index < self.frame_count);
+        self.mark_used(index);
+        self.ref_counts[index] = 1;
+
+        Some(self.frame_for_index(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PhysicalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = self.index_for_frame(frame);
+        assert!(self.ref_counts[index] > 0, "Double free of {:?}", frame);
+
+        self.ref_counts[index] -= 1;
+        if self.ref_counts[index] == 0 {
+            self.mark_free(index);
+        }
+    }
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+fn align_down(addr: u64) -> u64 {
+    addr & !(FRAME_SIZE - 1)
+}
@@ -0,0 +1,149 @@
+use crate::debug;
+use crate::sync::SpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+/// The heap never grows by less than this, so a burst of small allocations doesn't map page by page.
+const GROWTH_STEP: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub limit: usize,
+}
+
+/// The kernel heap. It starts out small and maps more pages right behind its current end
+/// whenever an allocation doesn't fit anymore, until it reaches its size limit.
+pub struct KernelHeap {
+    state: SpinLock<HeapState>,
+}
+
+struct HeapState {
+    heap: Heap,
+    /// First address behind the last mapped heap page. The heap itself might end a bit earlier,
+    /// if its start address isn't page aligned.
+    mapped_end: u64,
+    limit: usize,
+}
+
+impl KernelHeap {
+    pub const fn empty() -> Self {
+        Self {
+            state: SpinLock::new(HeapState {
+                heap: Heap::empty(),
+                mapped_end: 0,
+                limit: 0,
+            }),
+        }
+    }
+
+    /// Maps the first `initial_size` bytes at `start` and hands them to the heap.
+    /// The heap may later grow up to `limit` bytes, so nothing else may live in that range.
+    ///
+    /// # Safety
+    /// Must only be called once, after the memory manager is set up.
+    pub unsafe fn init(&self, start: u64, initial_size: usize, limit: usize) {
+        let mut state = self.state.lock();
+        state.limit = limit;
+        let first_page: Page = Page::containing_address(VirtAddr::new(start));
+        state.mapped_end = first_page.start_address().as_u64();
+
+        let mapped = state.map_until(start + initial_size as u64);
+        assert!(
+            mapped,
+            "Out of memory while mapping the initial kernel heap."
+        );
+
+        state.heap.init(start as usize, initial_size);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let state = self.state.lock();
+
+        HeapStats {
+            size: state.heap.size(),
+            used: state.heap.used(),
+            free: state.heap.free(),
+            limit: state.limit,
+        }
+    }
+}
+
+impl HeapState {
+    /// Grows the heap far enough that an allocation of `layout` fits behind its current end.
+    fn grow_for(&mut self, layout: Layout) -> bool {
+        let needed = layout.size() + layout.align();
+        let remaining = self.limit - self.heap.size();
+        if needed > remaining {
+            return false;
+        }
+
+        let growth = core::cmp::min(core::cmp::max(needed, GROWTH_STEP), remaining);
+        let new_top = self.heap.top() as u64 + growth as u64;
+        if !self.map_until(new_top) {
+            return false;
+        }
+
+        debug!(
+            "Growing kernel heap by {} KiB to {} KiB",
+            growth / 1024,
+            (self.heap.size() + growth) / 1024
+        );
+        unsafe {
+            self.heap.extend(growth);
+        }
+
+        true
+    }
+
+    /// Maps pages until everything below `end` is backed by memory.
+    fn map_until(&mut self, end: u64) -> bool {
+        while self.mapped_end < end {
+            let page = Page::containing_address(VirtAddr::new(self.mapped_end));
+            if super::map_kernel_page(page).is_none() {
+                return false;
+            }
+            self.mapped_end += page.size();
+        }
+
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut state = self.state.lock();
+        if let Ok(ptr) = state.heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        if state.grow_for(layout) {
+            if let Ok(ptr) = state.heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+
+        debug!(
+            "Kernel heap exhausted: failed to allocate {} bytes (align {}), {} of {} bytes in use, limit is {} bytes",
+            layout.size(),
+            layout.align(),
+            state.heap.used(),
+            state.heap.size(),
+            state.limit,
+        );
+
+        core::ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.state
+            .lock()
+            .heap
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
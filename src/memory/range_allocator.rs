@@ -0,0 +1,182 @@
+use crate::debug;
+
+/// How many separate free ranges a `VirtualRangeAllocator` can keep track of. Neighbouring free
+/// ranges are merged, so this only runs out if the window is badly fragmented.
+const MAX_FREE_RANGES: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RangeStats {
+    /// Size of the whole window in bytes.
+    pub size: u64,
+    pub used: u64,
+    pub free_ranges: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FreeRange {
+    start: u64,
+    end: u64,
+}
+
+/// Hands out page aligned ranges of a window of virtual address space. It only manages addresses,
+/// whoever allocates a range is responsible for mapping it.
+///
+/// Free ranges are kept in a sorted, fixed-size list, so the allocator never needs the heap and
+/// can be used to set up the heap's own memory.
+pub struct VirtualRangeAllocator {
+    window_start: u64,
+    window_end: u64,
+    free_ranges: [FreeRange; MAX_FREE_RANGES],
+    free_range_count: usize,
+}
+
+impl VirtualRangeAllocator {
+    /// Creates an allocator for the `size` bytes starting at `start`, which are all free.
+    pub fn new(start: u64, size: u64) -> Self {
+        let mut free_ranges = [FreeRange { start: 0, end: 0 }; MAX_FREE_RANGES];
+        free_ranges[0] = FreeRange {
+            start,
+            end: start + size,
+        };
+
+        Self {
+            window_start: start,
+            window_end: start + size,
+            free_ranges,
+            free_range_count: 1,
+        }
+    }
+
+    /// Finds `size` free bytes whose start is a multiple of `align` and returns their address.
+    /// Both have to be multiples of the page size.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        assert!(
+            size > 0 && size % 4096 == 0 && align.is_power_of_two() && align % 4096 == 0,
+            "Invalid virtual range request: {} bytes aligned to {}",
+            size,
+            align
+        );
+
+        let (index, start) = self.free_ranges[..self.free_range_count]
+            .iter()
+            .enumerate()
+            .find_map(|(index, range)| {
+                let start = align_up(range.start, align);
+                match start.checked_add(size) {
+                    Some(end) if end <= range.end => Some((index, start)),
+                    _ => None,
+                }
+            })?;
+
+        let range = self.free_ranges[index];
+        let end = start + size;
+        if range.start == start {
+            self.free_ranges[index].start = end;
+            if end == range.end {
+                self.remove(index);
+            }
+        } else {
+            self.free_ranges[index].end = start;
+            if end < range.end
+                && !self.insert(
+                    index + 1,
+                    FreeRange {
+                        start: end,
+                        end: range.end,
+                    },
+                )
+            {
+                debug!(
+                    "Too many free virtual ranges, losing {:#x}..{:#x}",
+                    end, range.end
+                );
+            }
+        }
+
+        Some(start)
+    }
+
+    /// Gives the `size` bytes at `start` back, merging them with adjacent free ranges.
+    pub fn free(&mut self, start: u64, size: u64) {
+        let end = start + size;
+        assert!(
+            self.window_start <= start && end <= self.window_end && start < end,
+            "{:#x}..{:#x} is not part of the virtual range window",
+            start,
+            end
+        );
+
+        let index = self.free_ranges[..self.free_range_count]
+            .iter()
+            .position(|range| range.start >= end)
+            .unwrap_or(self.free_range_count);
+
+        let previous = index.checked_sub(1).map(|index| self.free_ranges[index]);
+        let next = self.free_ranges[..self.free_range_count]
+            .get(index)
+            .copied();
+        if previous.map_or(false, |previous| previous.end > start) {
+            panic!("Double free of virtual range {:#x}..{:#x}", start, end);
+        }
+
+        let merges_previous = previous.map_or(false, |previous| previous.end == start);
+        let merges_next = next.map_or(false, |next| next.start == end);
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                self.free_ranges[index - 1].end = self.free_ranges[index].end;
+                self.remove(index);
+            }
+            (true, false) => self.free_ranges[index - 1].end = end,
+            (false, true) => self.free_ranges[index].start = start,
+            (false, false) => {
+                if !self.insert(index, FreeRange { start, end }) {
+                    debug!(
+                        "Too many free virtual ranges, losing {:#x}..{:#x}",
+                        start, end
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.window_start..self.window_end).contains(&addr)
+    }
+
+    pub fn stats(&self) -> RangeStats {
+        let free = self.free_ranges[..self.free_range_count]
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>();
+        let size = self.window_end - self.window_start;
+
+        RangeStats {
+            size,
+            used: size - free,
+            free_ranges: self.free_range_count,
+        }
+    }
+
+    fn insert(&mut self, index: usize, range: FreeRange) -> bool {
+        if self.free_range_count == MAX_FREE_RANGES {
+            return false;
+        }
+
+        self.free_ranges
+            .copy_within(index..self.free_range_count, index + 1);
+        self.free_ranges[index] = range;
+        self.free_range_count += 1;
+
+        true
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.free_ranges
+            .copy_within(index + 1..self.free_range_count, index);
+        self.free_range_count -= 1;
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
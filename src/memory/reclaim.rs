@@ -0,0 +1,138 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::{PageTable, PhysFrame};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Bootloader regions together with one bit per frame, set if the frame is still in use.
+struct BootloaderFrames {
+    regions: Vec<(u64, u64)>,
+    in_use: Vec<Vec<u64>>,
+}
+
+impl BootloaderFrames {
+    fn new(memory_map: &[MemoryRegion]) -> Self {
+        let regions = memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Bootloader)
+            .map(|region| {
+                (
+                    (region.start + FRAME_SIZE - 1) / FRAME_SIZE,
+                    region.end / FRAME_SIZE,
+                )
+            })
+            .filter(|(start, end)| start < end)
+            .collect::<Vec<_>>();
+        let in_use = regions
+            .iter()
+            .map(|(start, end)| vec![0; ((end - start + 63) / 64) as usize])
+            .collect();
+
+        Self { regions, in_use }
+    }
+
+    /// Marks every frame in `start..end` that lies in a bootloader region as in use.
+    fn mark(&mut self, start: PhysAddr, end: PhysAddr) {
+        let (start, end) = (start.as_u64() / FRAME_SIZE, end.as_u64() / FRAME_SIZE);
+        for (index, (region_start, region_end)) in self.regions.iter().enumerate() {
+            for frame in core::cmp::max(start, *region_start)..core::cmp::min(end, *region_end) {
+                let bit = (frame - region_start) as usize;
+                self.in_use[index][bit / 64] |= 1 << (bit % 64);
+            }
+        }
+    }
+
+    fn mark_frame(&mut self, frame: PhysFrame) {
+        self.mark(frame.start_address(), frame.start_address() + FRAME_SIZE);
+    }
+
+    fn unused_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.regions
+            .iter()
+            .zip(self.in_use.iter())
+            .flat_map(|((start, end), in_use)| {
+                (*start..*end).filter_map(move |frame| {
+                    let bit = (frame - start) as usize;
+                    if in_use[bit / 64] & (1 << (bit % 64)) != 0 {
+                        return None;
+                    }
+
+                    Some(PhysFrame::containing_address(PhysAddr::new(
+                        frame * FRAME_SIZE,
+                    )))
+                })
+            })
+    }
+}
+
+/// Finds every frame of the bootloader's regions that isn't referenced by the kernel's page tables.
+///
+/// Everything the kernel still uses from the bootloader (its own image, the page tables, the boot
+/// stack and the boot info) is mapped, so anything that isn't can go. The mapping of all physical
+/// memory at `memory_offset` doesn't count, only its page tables do.
+///
+/// This allocates, so it must not be called with the memory manager locked.
+///
+/// # Safety
+/// `l4_frame` has to be the kernel's level 4 table.
+pub unsafe fn unused_bootloader_frames(
+    memory_map: &[MemoryRegion],
+    l4_frame: PhysFrame,
+    memory_offset: u64,
+) -> Vec<PhysFrame> {
+    let mut frames = BootloaderFrames::new(memory_map);
+
+    let physical_memory_end = memory_map.iter().map(|region| region.end).max().unwrap();
+    let physical_mapping =
+        (memory_offset >> 39) as usize..=((memory_offset + physical_memory_end - 1) >> 39) as usize;
+
+    frames.mark_frame(l4_frame);
+    let l4_table = &*table_ptr(l4_frame, memory_offset);
+    for index in 0..512 {
+        let entry = &l4_table[index];
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            let mark_pages = !physical_mapping.contains(&index);
+            mark_table(&mut frames, entry.addr(), 3, mark_pages, memory_offset);
+        }
+    }
+
+    frames.unused_frames().collect()
+}
+
+/// Marks the table at `table_addr`, all tables below it and, if `mark_pages` is set, all pages
+/// they map. `level` is 3 for a PDPT and 1 for a page table.
+unsafe fn mark_table(
+    frames: &mut BootloaderFrames,
+    table_addr: PhysAddr,
+    level: u8,
+    mark_pages: bool,
+    memory_offset: u64,
+) {
+    let table_frame = PhysFrame::containing_address(table_addr);
+    frames.mark_frame(table_frame);
+
+    let table = &*table_ptr(table_frame, memory_offset);
+    for index in 0..512 {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if mark_pages {
+                // 4 KiB, 2 MiB or 1 GiB, depending on the level.
+                let page_size = FRAME_SIZE << (9 * (level - 1));
+                frames.mark(entry.addr(), entry.addr() + page_size);
+            }
+        } else {
+            mark_table(frames, entry.addr(), level - 1, mark_pages, memory_offset);
+        }
+    }
+}
+
+fn table_ptr(frame: PhysFrame, memory_offset: u64) -> *const PageTable {
+    (frame.start_address().as_u64() + memory_offset) as *const PageTable
+}
@@ -0,0 +1,99 @@
+use crate::memory;
+use crate::memory::{AddressSpace, USER_SPACE_START};
+use alloc::sync::Arc;
+use bmos_std::mem;
+use bmos_std::mem::{MemoryError, Protection, PAGE_SIZE};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Forks an address space and makes sure parent and child see each other's writes only until
+/// one of them writes to the shared page.
+pub fn copy_on_write() {
+    let addr = VirtAddr::new(USER_SPACE_START);
+    let value = addr.as_mut_ptr::<u64>();
+
+    // We switch address spaces underneath the scheduler, so nobody must interrupt us.
+    without_interrupts(|| unsafe {
+        let parent = Arc::new(AddressSpace::new().expect("Out of memory"));
+        parent
+            .reserve(
+                addr,
+                4096,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            )
+            .unwrap();
+        parent.activate();
+        value.write_volatile(1);
+
+        let child = Arc::new(parent.fork().expect("Out of memory"));
+        value.write_volatile(2);
+
+        child.activate();
+        assert_eq!(value.read_volatile(), 1, "Child saw the parent's write");
+        value.write_volatile(3);
+
+        parent.activate();
+        assert_eq!(value.read_volatile(), 2, "Parent saw the child's write");
+
+        child.activate();
+        assert_eq!(value.read_volatile(), 3, "Child lost its own write");
+
+        memory::kernel_address_space().activate();
+    });
+}
+
+/// Goes through the memory syscalls the way a userspace allocator would.
+pub fn memory_syscalls() {
+    let size = 2 * PAGE_SIZE;
+    let protection = Protection::READ | Protection::WRITE;
+
+    let addr = mem::reserve(None, size, protection).expect("Failed to reserve memory");
+    assert_eq!(
+        mem::reserve(Some(addr), size, protection),
+        Err(MemoryError::Overlapping)
+    );
+    mem::map(addr, size).expect("Failed to map reserved memory");
+
+    unsafe {
+        addr.add(PAGE_SIZE).write_volatile(42);
+        mem::protect(addr, PAGE_SIZE, Protection::READ).expect("Failed to protect memory");
+        assert_eq!(addr.add(PAGE_SIZE).read_volatile(), 42);
+        mem::unmap(addr, size).expect("Failed to unmap memory");
+    }
+
+    let again = mem::reserve(Some(addr), size, protection).expect("Failed to reserve memory");
+    unsafe {
+        assert_eq!(
+            again.add(PAGE_SIZE).read_volatile(),
+            0,
+            "Unmapped memory was reused"
+        );
+        mem::unmap(again, size).expect("Failed to unmap memory");
+    }
+}
+
+/// Allocates, frees and re-allocates kernel pages to check alignment and that freed ranges
+/// are merged again.
+pub fn kernel_pages() {
+    let first = memory::allocate_kernel_pages(1, 4096).expect("Out of memory");
+    let aligned = memory::allocate_kernel_pages(4, 64 * 1024).expect("Out of memory");
+    assert!(aligned.start.start_address().is_aligned(64 * 1024u64));
+
+    unsafe {
+        aligned
+            .start
+            .start_address()
+            .as_mut_ptr::<u64>()
+            .write_volatile(42);
+    }
+
+    memory::free_kernel_pages(first);
+    memory::free_kernel_pages(aligned);
+
+    // Both ranges and the padding between them are free again, so they have to fit as one.
+    let size = (aligned.end.start_address() - first.start.start_address()) / 4096;
+    let merged = memory::allocate_kernel_pages(size, 4096).expect("Out of memory");
+    assert_eq!(merged.start, first.start, "Freed ranges were not merged");
+    memory::free_kernel_pages(merged);
+}
@@ -0,0 +1,217 @@
+use crate::sync::SpinLock;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+pub const SIZE_CLASS_COUNT: usize = 8;
+const SIZE_CLASSES: [usize; SIZE_CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+const SLAB_SIZE: u64 = 4096;
+
+/// Freed objects are filled with this byte if the `slab-poison` feature is enabled.
+const POISON_BYTE: u8 = 0xde;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    pub object_size: usize,
+    /// Pages handed to this size class so far.
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+/// Serves small allocations from per-size-class free lists. Every size class carves whole pages
+/// into equally sized objects, so allocating and freeing is a single list operation and small,
+/// short-lived objects don't fragment the linked-list heap.
+///
+/// Slab pages come from their own virtual window and are never given back; freed objects stay on
+/// their size class' free list.
+pub struct SlabAllocator {
+    caches: [SpinLock<SlabCache>; SIZE_CLASS_COUNT],
+    window_start: u64,
+    window_size: u64,
+    next_slab: AtomicU64,
+}
+
+struct SlabCache {
+    free_list: Option<NonNull<FreeObject>>,
+    stats: SlabStats,
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+// The free list only points into slab pages, which are owned by the cache.
+unsafe impl Send for SlabCache {}
+
+impl SlabAllocator {
+    pub const fn new(window_start: u64, window_size: u64) -> Self {
+        Self {
+            caches: [
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[0])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[1])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[2])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[3])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[4])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[5])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[6])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[7])),
+            ],
+            window_start,
+            window_size,
+            next_slab: AtomicU64::new(window_start),
+        }
+    }
+
+    /// Returns `None` if `layout` is too large for the slabs or no slab page could be mapped,
+    /// in which case the caller should fall back to the heap.
+    pub fn allocate(&self, layout: Layout) -> Option<*mut u8> {
+        let class = size_class(layout)?;
+        let mut cache = self.caches[class].lock();
+
+        if cache.free_list.is_none() {
+            let slab = self.map_slab()?;
+            cache.add_slab(slab);
+        }
+
+        let object = cache.pop()?;
+        Some(object.as_ptr() as *mut u8)
+    }
+
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` for the same `layout`.
+    pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        let class = size_class(layout).expect("Layout was never served by the slab allocator");
+        self.caches[class]
+            .lock()
+            .push(NonNull::new_unchecked(ptr as *mut FreeObject));
+    }
+
+    /// Tells whether `ptr` points into a slab page.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as u64;
+        addr >= self.window_start && addr < self.next_slab.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> [SlabStats; SIZE_CLASS_COUNT] {
+        let mut stats = [SlabStats::default(); SIZE_CLASS_COUNT];
+        for (class, cache) in self.caches.iter().enumerate() {
+            stats[class] = cache.lock().stats;
+        }
+
+        stats
+    }
+
+    fn map_slab(&self) -> Option<VirtAddr> {
+        let addr = self.next_slab.fetch_add(SLAB_SIZE, Ordering::Relaxed);
+        if addr + SLAB_SIZE > self.window_start + self.window_size {
+            self.next_slab.fetch_sub(SLAB_SIZE, Ordering::Relaxed);
+            return None;
+        }
+
+        let page = Page::containing_address(VirtAddr::new(addr));
+        super::map_kernel_page(page)?;
+
+        Some(page.start_address())
+    }
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            free_list: None,
+            stats: SlabStats {
+                object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                objects_free: 0,
+                allocations: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    fn add_slab(&mut self, slab: VirtAddr) {
+        let object_size = self.stats.object_size;
+        let objects = SLAB_SIZE as usize / object_size;
+
+        // Push in reverse so the objects are handed out in address order.
+        for index in (0..objects).rev() {
+            let object = (slab + index * object_size).as_mut_ptr::<FreeObject>();
+            unsafe {
+                self.link(NonNull::new_unchecked(object));
+            }
+        }
+
+        self.stats.slabs += 1;
+        self.stats.objects_free += objects;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<FreeObject>> {
+        let object = self.free_list?;
+        unsafe {
+            self.free_list = object.as_ref().next;
+            check_poison(object, self.stats.object_size);
+        }
+
+        self.stats.objects_free -= 1;
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+
+        Some(object)
+    }
+
+    unsafe fn push(&mut self, object: NonNull<FreeObject>) {
+        self.link(object);
+
+        self.stats.objects_free += 1;
+        self.stats.objects_in_use -= 1;
+        self.stats.frees += 1;
+    }
+
+    unsafe fn link(&mut self, object: NonNull<FreeObject>) {
+        if cfg!(feature = "slab-poison") {
+            core::ptr::write_bytes(
+                object.as_ptr() as *mut u8,
+                POISON_BYTE,
+                self.stats.object_size,
+            );
+        }
+
+        object.as_ptr().write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = Some(object);
+    }
+}
+
+/// Panics if anything but the free list link was written to `object` since it was freed.
+unsafe fn check_poison(object: NonNull<FreeObject>, object_size: usize) {
+    if !cfg!(feature = "slab-poison") {
+        return;
+    }
+
+    let link_size = core::mem::size_of::<FreeObject>();
+    let bytes = core::slice::from_raw_parts(
+        (object.as_ptr() as *const u8).add(link_size),
+        object_size - link_size,
+    );
+    if let Some(offset) = bytes.iter().position(|byte| *byte != POISON_BYTE) {
+        panic!(
+            "Use after free: {}-byte slab object at {:p} was modified at offset {}",
+            object_size,
+            object.as_ptr(),
+            offset + link_size
+        );
+    }
+}
+
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|class| size <= *class)
+}
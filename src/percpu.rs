@@ -0,0 +1,147 @@
+use crate::scheduler::Scheduler;
+use crate::threading::Thread;
+use alloc::boxed::Box;
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::ptr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+pub mod selftest;
+
+/// What every CPU keeps for itself. GS base points to it, and its first field points back to it,
+/// so a single `mov` from `gs:0` finds it.
+///
+/// Only the CPU it belongs to touches it, which is why plain cells are enough. The thread that
+/// looks at it must not move to another CPU in the meantime, so interrupts or preemption have to
+/// be disabled.
+#[repr(C)]
+pub struct PerCpu {
+    this: *const PerCpu,
+    cpu_id: usize,
+    current_thread: Cell<*mut Thread>,
+    /// Timer ticks don't switch threads while this isn't zero, see `disable_preemption`.
+    preempt_count: Cell<usize>,
+    context_switches: Cell<u64>,
+    scheduler: UnsafeCell<Option<Box<dyn Scheduler>>>,
+}
+
+impl PerCpu {
+    const fn new(cpu_id: usize) -> Self {
+        Self {
+            this: ptr::null(),
+            cpu_id,
+            current_thread: Cell::new(ptr::null_mut()),
+            preempt_count: Cell::new(0),
+            context_switches: Cell::new(0),
+            scheduler: UnsafeCell::new(None),
+        }
+    }
+}
+
+/// The bootstrap processor's data can't come from the heap, since it is needed before the heap
+/// is set up.
+static mut BSP_DATA: PerCpu = PerCpu::new(0);
+
+/// Points GS base at the bootstrap processor's data. Runs before anything else in the kernel.
+pub fn init_bsp() {
+    unsafe { install(&mut BSP_DATA) };
+}
+
+/// Gives the calling application processor its own data. Runs before anything else on that CPU.
+pub fn init_ap(cpu_id: usize) {
+    install(Box::leak(Box::new(PerCpu::new(cpu_id))));
+}
+
+fn install(data: &'static mut PerCpu) {
+    let this = data as *const PerCpu;
+    data.this = this;
+    GsBase::write(VirtAddr::from_ptr(this));
+}
+
+/// Whether the calling CPU has its data yet. Application processors run a bit of code before.
+pub fn is_installed() -> bool {
+    !GsBase::read().is_null()
+}
+
+fn get() -> &'static PerCpu {
+    unsafe {
+        let data: *const PerCpu;
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) data,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*data
+    }
+}
+
+/// Index of the CPU this runs on. The bootstrap processor is CPU 0.
+pub fn cpu_id() -> usize {
+    get().cpu_id
+}
+
+/// The thread running on this CPU, null until the scheduler is set up.
+pub fn current_thread() -> *mut Thread {
+    get().current_thread.get()
+}
+
+/// Called by the scheduler whenever it switches to another thread.
+pub(crate) fn set_current_thread(thread: *mut Thread) {
+    let data = get();
+    data.current_thread.set(thread);
+    data.context_switches.set(data.context_switches.get() + 1);
+}
+
+/// Number of times this CPU switched to a thread, counting the first one it ran.
+pub fn context_switches() -> u64 {
+    get().context_switches.get()
+}
+
+/// The scheduler of the calling CPU, see `scheduler::local`.
+///
+/// # Safety
+/// Interrupts have to be disabled as long as the scheduler is used.
+pub(crate) unsafe fn scheduler() -> Option<&'static mut dyn Scheduler> {
+    (*get().scheduler.get()).as_deref_mut()
+}
+
+/// Installs the scheduler of the calling CPU.
+pub(crate) fn set_scheduler(scheduler: Box<dyn Scheduler>) {
+    without_interrupts(|| unsafe { *get().scheduler.get() = Some(scheduler) });
+}
+
+/// Keeps the calling thread running on its CPU until the returned guard is dropped. Interrupts
+/// stay enabled, but the timer doesn't switch threads in the meantime. Guards can be nested.
+///
+/// The thread must not block, sleep or yield while it holds a guard. A tick that would have
+/// switched threads isn't made up for when the guard is dropped, the next one does that.
+pub fn disable_preemption() -> PreemptGuard {
+    // Otherwise we could move to another CPU between finding the counter and bumping it.
+    without_interrupts(|| {
+        let data = get();
+        data.preempt_count.set(data.preempt_count.get() + 1);
+    });
+
+    PreemptGuard {
+        _not_send: PhantomData,
+    }
+}
+
+pub fn preemption_disabled() -> bool {
+    get().preempt_count.get() > 0
+}
+
+/// Returned by `disable_preemption`, enables preemption again once it is dropped.
+pub struct PreemptGuard {
+    /// The guard has to be dropped on the CPU that counted it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        let data = get();
+        data.preempt_count.set(data.preempt_count.get() - 1);
+    }
+}
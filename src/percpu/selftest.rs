@@ -0,0 +1,35 @@
+use crate::percpu;
+use crate::selftest::{join_all, spawn_contenders};
+use crate::timer;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Spins for a while with preemption disabled, while other threads want to run, and makes sure
+/// the CPU never switched threads in the meantime.
+pub fn preemption() {
+    const TICKS: u64 = 20;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let spinner_stop = stop.clone();
+    let spinners = spawn_contenders("selftest-spin", move |_| {
+        while !spinner_stop.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    });
+
+    let guard = percpu::disable_preemption();
+    let switches = percpu::context_switches();
+    let start = timer::ticks();
+    while timer::ticks() < start + TICKS {
+        core::hint::spin_loop();
+    }
+    assert_eq!(
+        percpu::context_switches(),
+        switches,
+        "Switched threads with preemption disabled"
+    );
+    drop(guard);
+
+    stop.store(true, Ordering::Relaxed);
+    join_all(spinners);
+}
@@ -1,70 +1,798 @@
 use crate::cpu;
 use crate::debug;
-use crate::threading::Thread;
+use crate::percpu;
+use crate::smp;
+use crate::smp::MAX_CPUS;
+use crate::sync::{Semaphore, SpinLock};
+use crate::threading;
+use crate::threading::{Priority, Thread, ThreadId, ThreadState};
 use alloc::boxed::Box;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::cell::RefCell;
+use core::cell::{Ref, RefCell};
 use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::FsBase;
+
+pub mod selftest;
+
+/// Decides which thread runs next on one CPU. Every implementation keeps the running thread, the
+/// run queue and the blocked and dead threads, and switches between threads when one of these is
+/// called.
+///
+/// All of these have to be called with interrupts disabled, on the CPU the scheduler belongs to.
+pub trait Scheduler {
+    fn name(&self) -> &'static str;
+
+    fn add_task(&mut self, task: Pin<Box<Thread>>);
+
+    /// Called by the timer interrupt handler, may switch to another task.
+    fn tick(&mut self);
+
+    /// Lets other ready tasks run before the running one continues.
+    fn yield_now(&mut self);
+
+    /// Blocks the running task until `wake` is called with its ID.
+    fn block_current(&mut self);
+
+    /// Puts a blocked task back into the run queue.
+    fn wake(&mut self, id: ThreadId);
+
+    /// Switches away from the current task for good. It has to be marked as dead already.
+    fn thank_you_next(&mut self);
+
+    /// Returns the running task, unless the scheduler is in the middle of switching tasks.
+    fn current_task(&self) -> Option<Ref<Pin<Box<Thread>>>>;
+
+    /// Ticks during which the idle task was running.
+    fn idle_ticks(&self) -> u64;
+
+    /// Number of tasks that are ready or running, not counting the idle task.
+    fn load(&self) -> usize;
+
+    /// Removes a ready task, preferably an unimportant one, so it can move to another CPU.
+    fn take_ready_task(&mut self) -> Option<Pin<Box<Thread>>>;
+
+    /// Hands over the tasks that died since the last call, so they can be freed.
+    fn take_dead_tasks(&mut self) -> Vec<Pin<Box<Thread>>>;
+
+    /// Calls `f` with every task the scheduler holds, including the idle task.
+    fn for_each_task(&self, f: &mut dyn FnMut(&Thread));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerKind {
+    RoundRobin,
+    Feedback,
+}
+
+impl SchedulerKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "round-robin" => Some(SchedulerKind::RoundRobin),
+            "mlfq" => Some(SchedulerKind::Feedback),
+            _ => None,
+        }
+    }
+
+    /// Creates the scheduler. `initial_task` stands for the code that is running right now. It is
+    /// switched away from and back to like any other task.
+    pub fn create(self, initial_task: Pin<Box<Thread>>) -> Box<dyn Scheduler> {
+        match self {
+            SchedulerKind::RoundRobin => Box::new(RoundRobinScheduler::new(initial_task)),
+            SchedulerKind::Feedback => Box::new(FeedbackScheduler::new(initial_task)),
+        }
+    }
+}
+
+// Every CPU has its own scheduler in its per-CPU data, which only that CPU touches, with interrupts
+// disabled. Threads stay on the CPU they were placed on, unless the load balancer moves them while
+// they are ready, so everything other CPUs want from a scheduler goes through its inbox.
+
+/// The kind of scheduler every CPU uses, picked by the bootstrap processor.
+static mut KIND: SchedulerKind = SchedulerKind::Feedback;
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+const ZERO_TICKS: AtomicU64 = AtomicU64::new(0);
+/// What `Scheduler::load` returned on every CPU at its last tick, plus the tasks placed on it since.
+static LOADS: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+static TICKS: [AtomicU64; MAX_CPUS] = [ZERO_TICKS; MAX_CPUS];
+static IDLE_TICKS: [AtomicU64; MAX_CPUS] = [ZERO_TICKS; MAX_CPUS];
+
+/// Every `BALANCE_INTERVAL` ticks, a CPU hands one of its ready tasks to the least loaded CPU if
+/// that one has at least two tasks less.
+const BALANCE_INTERVAL: u64 = 10;
+
+/// Tasks that died on any CPU, waiting for the reaper.
+static DEAD_TASKS: SpinLock<Vec<Pin<Box<Thread>>>> = SpinLock::new(Vec::new());
+
+lazy_static! {
+    /// The CPU every task that isn't dead belongs to.
+    static ref DIRECTORY: SpinLock<BTreeMap<ThreadId, usize>> = SpinLock::new(BTreeMap::new());
+    static ref INBOXES: Vec<SpinLock<Inbox>> =
+        (0..MAX_CPUS).map(|_| SpinLock::new(Inbox::default())).collect();
+}
+
+/// What other CPUs left for a CPU's scheduler. It is emptied whenever the CPU gets a reschedule
+/// interrupt, yields or ticks.
+#[derive(Default)]
+struct Inbox {
+    tasks: Vec<Pin<Box<Thread>>>,
+    wakes: Vec<ThreadId>,
+    snapshots: Vec<Arc<SnapshotRequest>>,
+}
+
+/// What `snapshot` tells about a thread.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    /// The CPU whose scheduler holds the thread.
+    pub cpu: usize,
+    pub ticks: u64,
+    pub context_switches: u64,
+    pub created_at: u64,
+}
+
+/// Collects the tasks of every CPU for `snapshot`.
+struct SnapshotRequest {
+    tasks: SpinLock<Vec<TaskInfo>>,
+    /// Released by every other CPU once it added its tasks.
+    done: Semaphore,
+}
+
+/// Creates the bootstrap processor's scheduler. `initial_task` stands for the code that is
+/// running right now, see `SchedulerKind::create`.
+pub fn init(kind: SchedulerKind, initial_task: Pin<Box<Thread>>) {
+    unsafe {
+        KIND = kind;
+    }
+    init_ap(initial_task);
+
+    debug!("Using the {} scheduler", unsafe { local().name() });
+}
+
+/// Creates the scheduler of the calling CPU, of the same kind as the bootstrap processor's.
+pub fn init_ap(initial_task: Pin<Box<Thread>>) {
+    DIRECTORY.lock().insert(initial_task.id, percpu::cpu_id());
+    percpu::set_scheduler(unsafe { KIND }.create(initial_task));
+}
+
+/// The scheduler of the calling CPU.
+///
+/// # Safety
+/// Interrupts have to be disabled as long as the scheduler is used. Otherwise the timer could
+/// reenter it, or the thread could continue on another CPU.
+pub unsafe fn local() -> &'static mut dyn Scheduler {
+    try_local().expect("The scheduler isn't initialized")
+}
+
+/// Like `local`, but returns `None` instead of panicking if the scheduler isn't set up yet.
+///
+/// # Safety
+/// See `local`.
+pub unsafe fn try_local() -> Option<&'static mut dyn Scheduler> {
+    percpu::scheduler()
+}
+
+/// Starts `task` on the CPU with the lowest load.
+pub fn add_task(task: Pin<Box<Thread>>) {
+    without_interrupts(|| {
+        let cpu = least_loaded_cpu();
+        // Count it right away, so a burst of new tasks is spread over all CPUs.
+        LOADS[cpu].fetch_add(1, Ordering::Relaxed);
+        send_task(cpu, task);
+    });
+}
+
+/// Puts the blocked task `id` back into the run queue of its CPU.
+pub fn wake(id: ThreadId) {
+    without_interrupts(|| {
+        let cpu = match DIRECTORY.lock().get(&id) {
+            Some(cpu) => *cpu,
+            None => return,
+        };
+
+        if cpu == percpu::cpu_id() {
+            unsafe { local().wake(id) };
+        } else {
+            // The task can only be blocked once its CPU looks at the inbox, since blocking
+            // happens with interrupts disabled.
+            INBOXES[cpu].lock().wakes.push(id);
+            smp::send_reschedule(cpu);
+        }
+    });
+}
+
+/// Called by the timer interrupt handler of every CPU.
+pub fn tick() {
+    let cpu = percpu::cpu_id();
+    let ticks = TICKS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    // Counted here, since the scheduler doesn't see the ticks while preemption is disabled.
+    unsafe { (*percpu::current_thread()).ticks += 1 };
+    handle_reschedule();
+
+    let scheduler = unsafe { local() };
+    LOADS[cpu].store(scheduler.load(), Ordering::Relaxed);
+    IDLE_TICKS[cpu].store(scheduler.idle_ticks(), Ordering::Relaxed);
+    if ticks % BALANCE_INTERVAL == 0 {
+        balance(cpu);
+    }
+
+    // May switch to another task, so it comes last.
+    if !percpu::preemption_disabled() {
+        scheduler.tick();
+    }
+}
+
+/// Lets other ready tasks on this CPU run before the calling task continues.
+pub fn yield_now() {
+    without_interrupts(|| {
+        handle_reschedule();
+        unsafe { local().yield_now() };
+    });
+}
+
+/// Takes whatever other CPUs left in this CPU's inbox, and hands the tasks that died here to
+/// the reaper. Called by the reschedule interrupt handler.
+pub fn handle_reschedule() {
+    let cpu = percpu::cpu_id();
+    let inbox = core::mem::take(&mut *INBOXES[cpu].lock());
+    let scheduler = unsafe { local() };
+    for task in inbox.tasks {
+        scheduler.add_task(task);
+    }
+    for id in inbox.wakes {
+        scheduler.wake(id);
+    }
+
+    // We aren't running on the stack of any of them anymore, so they can be freed now.
+    let dead = scheduler.take_dead_tasks();
+    if !dead.is_empty() {
+        let mut directory = DIRECTORY.lock();
+        for task in dead.iter() {
+            directory.remove(&task.id);
+        }
+        drop(directory);
+
+        DEAD_TASKS.lock().extend(dead);
+        threading::wake_reaper();
+    }
+
+    for request in inbox.snapshots {
+        report_tasks(&request);
+        request.done.release();
+    }
+}
+
+/// Describes every task on every CPU, ordered by ID. Blocks until all CPUs answered. Tasks that
+/// move to another CPU in the meantime may show up twice or not at all.
+pub fn snapshot() -> Vec<TaskInfo> {
+    let request = Arc::new(SnapshotRequest {
+        tasks: SpinLock::new(Vec::new()),
+        done: Semaphore::new(0),
+    });
+
+    let others = without_interrupts(|| {
+        let current = percpu::cpu_id();
+        let others = (0..smp::cpu_count()).filter(|&cpu| cpu != current);
+        for cpu in others.clone() {
+            INBOXES[cpu].lock().snapshots.push(request.clone());
+            smp::send_reschedule(cpu);
+        }
+
+        report_tasks(&request);
+        others.count()
+    });
+    for _ in 0..others {
+        request.done.acquire();
+    }
+
+    let mut tasks = core::mem::take(&mut *request.tasks.lock());
+    tasks.sort_by_key(|task| task.id);
+    tasks
+}
+
+/// Adds the tasks of the calling CPU to `request`. Has to be called with interrupts disabled.
+fn report_tasks(request: &SnapshotRequest) {
+    let cpu = percpu::cpu_id();
+    let mut tasks = request.tasks.lock();
+    unsafe { local() }.for_each_task(&mut |task| {
+        tasks.push(TaskInfo {
+            id: task.id,
+            name: task.name.clone(),
+            state: task.state,
+            cpu,
+            ticks: task.ticks,
+            context_switches: task.context_switches,
+            created_at: task.created_at,
+        })
+    });
+}
+
+pub fn has_dead_tasks() -> bool {
+    !DEAD_TASKS.lock().is_empty()
+}
+
+/// Hands over the tasks that died on any CPU, so they can be freed.
+pub fn take_dead_tasks() -> Vec<Pin<Box<Thread>>> {
+    core::mem::take(&mut *DEAD_TASKS.lock())
+}
+
+/// Ticks during which any of the CPUs ran its idle task.
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS
+        .iter()
+        .map(|ticks| ticks.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Ticks counted by all CPUs together.
+pub fn ticks() -> u64 {
+    TICKS
+        .iter()
+        .map(|ticks| ticks.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Moves a ready task from `cpu` to the least loaded CPU, if that evens out the load.
+fn balance(cpu: usize) {
+    let target = least_loaded_cpu();
+    if LOADS[cpu].load(Ordering::Relaxed) < LOADS[target].load(Ordering::Relaxed) + 2 {
+        return;
+    }
+
+    if let Some(task) = unsafe { local() }.take_ready_task() {
+        debug!(
+            "Moving thread '{}' ({}) from CPU {} to CPU {}",
+            task.name, task.id, cpu, target
+        );
+        LOADS[cpu].fetch_sub(1, Ordering::Relaxed);
+        LOADS[target].fetch_add(1, Ordering::Relaxed);
+        send_task(target, task);
+    }
+}
+
+/// The CPU with the lowest load, preferring the calling one.
+fn least_loaded_cpu() -> usize {
+    let current = percpu::cpu_id();
+    (0..smp::cpu_count())
+        .min_by_key(|&cpu| (LOADS[cpu].load(Ordering::Relaxed), cpu != current))
+        .unwrap_or(current)
+}
+
+/// Adds the ready `task` to the run queue of `cpu`.
+fn send_task(cpu: usize, task: Pin<Box<Thread>>) {
+    DIRECTORY.lock().insert(task.id, cpu);
+    if cpu == percpu::cpu_id() {
+        unsafe { local().add_task(task) };
+    } else {
+        INBOXES[cpu].lock().tasks.push(task);
+        smp::send_reschedule(cpu);
+    }
+}
+
+/// The tasks every scheduler has to keep track of, no matter how it orders the ready ones.
+///
+/// This includes the idle task, which runs whenever no other task is ready. It never shows up in
+/// a run queue and is never cleaned up.
+struct Tasks {
+    current: RefCell<Pin<Box<Thread>>>,
+    blocked: Vec<Pin<Box<Thread>>>,
+    dead: Vec<Pin<Box<Thread>>>,
+    /// `None` while the idle task is running.
+    idle: Option<Pin<Box<Thread>>>,
+    idle_id: ThreadId,
+    idle_ticks: u64,
+}
+
+impl Tasks {
+    fn new(mut initial_task: Pin<Box<Thread>>) -> Self {
+        initial_task.as_mut().set_state(ThreadState::Running);
+        let idle = threading::build_idle();
+        percpu::set_current_thread(&*initial_task as *const Thread as *mut Thread);
+        FsBase::write(initial_task.fs_base);
+
+        Self {
+            current: RefCell::new(initial_task),
+            blocked: Vec::new(),
+            dead: Vec::new(),
+            idle_id: idle.id,
+            idle: Some(idle),
+            idle_ticks: 0,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.idle.is_none()
+    }
+
+    /// 1 if a task other than the idle task is running.
+    fn running_load(&self) -> usize {
+        if self.is_idle() {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Accounts the tick to the idle task if it is running and returns whether it is.
+    fn idle_tick(&mut self) -> bool {
+        if self.is_idle() {
+            self.idle_ticks += 1;
+        }
+
+        self.is_idle()
+    }
+
+    fn current(&self) -> Option<Ref<Pin<Box<Thread>>>> {
+        self.current.try_borrow().ok()
+    }
+
+    /// Calls `f` with every task except the ready ones, which the schedulers keep themselves.
+    fn for_each(&self, f: &mut dyn FnMut(&Thread)) {
+        if let Some(current) = self.current() {
+            f(&current);
+        }
+        for task in self
+            .blocked
+            .iter()
+            .chain(self.dead.iter())
+            .chain(self.idle.iter())
+        {
+            f(task);
+        }
+    }
+
+    fn block_current(&mut self) {
+        self.current
+            .borrow_mut()
+            .as_mut()
+            .set_state(ThreadState::Blocked);
+    }
+
+    /// Removes the blocked task `id` and marks it as ready.
+    fn take_blocked(&mut self, id: ThreadId) -> Option<Pin<Box<Thread>>> {
+        if let Some(index) = self.blocked.iter().position(|task| task.id == id) {
+            let mut task = self.blocked.remove(index);
+            task.as_mut().set_state(ThreadState::Ready);
+            return Some(task);
+        }
+
+        None
+    }
+
+    /// Switches to `next`, or to the idle task if there is no ready task and the current one
+    /// can't keep running. What happens to the current task depends on its state: running tasks
+    /// are handed to `requeue`, blocked ones wait for `wake` and dead ones are kept until the reaper
+    /// takes them.
+    fn switch_to<F>(&mut self, next: Option<Pin<Box<Thread>>>, requeue: F)
+    where
+        F: FnOnce(Pin<Box<Thread>>),
+    {
+        // Ticks don't get here while preemption is disabled.
+        debug_assert!(
+            !percpu::preemption_disabled(),
+            "A thread blocked or yielded with preemption disabled"
+        );
+        let mut next = match next {
+            Some(task) => task,
+            None if self.current.borrow().state == ThreadState::Running => return,
+            None => self.idle.take().expect("The idle task can't block or exit"),
+        };
+
+        let new_addr = (&*next) as *const Thread;
+        next.as_mut().set_state(ThreadState::Running);
+        next.as_mut().count_switch();
+        next.address_space.activate();
+        // Only the thread itself changes its FS base, and it updates `fs_base` when it does.
+        FsBase::write(next.fs_base);
+        percpu::set_current_thread(new_addr as *mut Thread);
+
+        let mut old_task = self.current.replace(next);
+
+        let old_addr = (&*old_task) as *const Thread;
+
+        match old_task.state {
+            _ if old_task.id == self.idle_id => {
+                old_task.as_mut().set_state(ThreadState::Ready);
+                self.idle = Some(old_task);
+            }
+            ThreadState::Running | ThreadState::Ready => {
+                old_task.as_mut().set_state(ThreadState::Ready);
+                requeue(old_task);
+            }
+            ThreadState::Blocked => self.blocked.push(old_task),
+            ThreadState::Dead => self.dead.push(old_task),
+        }
+
+        unsafe {
+            debug!("old: {:x?}, new: {:?}", old_addr, new_addr);
+            cpu::switch_context(old_addr, new_addr);
+        }
+    }
+}
 
 const TASK_MAX_TICKS: u8 = 10;
 
+/// Runs every ready task for `TASK_MAX_TICKS` ticks in turn. Priorities are ignored.
 pub struct RoundRobinScheduler {
     current_task_ticks: u8,
-    current_task: RefCell<Pin<Box<Thread>>>,
-    tasks: VecDeque<Pin<Box<Thread>>>,
-    dead_tasks: Vec<Pin<Box<Thread>>>,
+    tasks: Tasks,
+    run_queue: VecDeque<Pin<Box<Thread>>>,
 }
 
 impl RoundRobinScheduler {
     pub fn new(initial_task: Pin<Box<Thread>>) -> Self {
         Self {
             current_task_ticks: 0,
-            current_task: RefCell::new(initial_task),
-            tasks: VecDeque::new(),
-            dead_tasks: Vec::new(),
+            tasks: Tasks::new(initial_task),
+            run_queue: VecDeque::new(),
         }
     }
 
-    pub fn add_task(&mut self, task: Pin<Box<Thread>>) {
-        self.tasks.push_back(task);
+    fn next_task(&mut self) {
+        self.current_task_ticks = 0;
+        let next_task = self.run_queue.pop_front();
+
+        let run_queue = &mut self.run_queue;
+        self.tasks
+            .switch_to(next_task, |task| run_queue.push_back(task));
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn add_task(&mut self, task: Pin<Box<Thread>>) {
+        self.run_queue.push_back(task);
     }
 
-    pub fn tick(&mut self) {
+    fn tick(&mut self) {
+        if self.tasks.idle_tick() {
+            return;
+        }
+
         self.current_task_ticks += 1;
         if self.current_task_ticks == TASK_MAX_TICKS {
-            self.current_task_ticks = 0;
-            self.next_task(true);
+            self.next_task();
         }
     }
 
-    fn next_task(&mut self, reschedule: bool) {
-        let mut next_task = match self.tasks.pop_front() {
-            Some(task) => task,
-            None => return,
-        };
-        let new_addr = (&*next_task) as *const Thread;
+    fn yield_now(&mut self) {
+        self.next_task();
+    }
 
-        let mut old_task = self.current_task.replace(next_task);
+    fn block_current(&mut self) {
+        self.tasks.block_current();
+        self.next_task();
+    }
 
-        let old_addr = (&*old_task) as *const Thread;
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(task) = self.tasks.take_blocked(id) {
+            self.run_queue.push_back(task);
+        }
+    }
+
+    fn thank_you_next(&mut self) {
+        debug_assert_eq!(self.tasks.current.borrow().state, ThreadState::Dead);
+        self.next_task();
+    }
+
+    fn current_task(&self) -> Option<Ref<Pin<Box<Thread>>>> {
+        self.tasks.current()
+    }
 
-        if reschedule && old_task.name != "main" {
-            self.tasks.push_back(old_task);
-        } else if old_task.name != "main" {
-            self.dead_tasks.push(old_task);
-        } else if old_task.name == "main" {
-            drop(old_task);
+    fn idle_ticks(&self) -> u64 {
+        self.tasks.idle_ticks
+    }
+
+    fn load(&self) -> usize {
+        self.run_queue.len() + self.tasks.running_load()
+    }
+
+    fn take_ready_task(&mut self) -> Option<Pin<Box<Thread>>> {
+        self.run_queue.pop_back()
+    }
+
+    fn take_dead_tasks(&mut self) -> Vec<Pin<Box<Thread>>> {
+        core::mem::take(&mut self.tasks.dead)
+    }
+
+    fn for_each_task(&self, f: &mut dyn FnMut(&Thread)) {
+        self.tasks.for_each(f);
+        for task in self.run_queue.iter() {
+            f(task);
         }
+    }
+}
 
-        unsafe {
-            debug!("old: {:x?}, new: {:?}", old_addr, new_addr);
-            cpu::switch_context(old_addr, new_addr);
+/// Number of run queues of the feedback scheduler. Level 0 is scheduled first.
+const LEVELS: usize = 4;
+
+/// Every `BOOST_INTERVAL` ticks all tasks move back to the level of their priority, so tasks that
+/// were demoted for using a lot of CPU time can't starve.
+const BOOST_INTERVAL: u32 = 100;
+
+/// A multi-level feedback queue.
+///
+/// Tasks start at the level of their priority. A task that uses up its whole time slice is
+/// demoted one level, where time slices are twice as long, while a task that blocks early keeps
+/// its level. Interactive tasks, like the terminal, therefore stay ahead of busy background
+/// tasks. Waking a task only queues it, it preempts a less important task at the next tick.
+pub struct FeedbackScheduler {
+    current_task_ticks: u8,
+    ticks_until_boost: u32,
+    tasks: Tasks,
+    queues: [VecDeque<Pin<Box<Thread>>>; LEVELS],
+}
+
+impl FeedbackScheduler {
+    pub fn new(mut initial_task: Pin<Box<Thread>>) -> Self {
+        let level = base_level(initial_task.priority);
+        initial_task.as_mut().set_level(level);
+
+        Self {
+            current_task_ticks: 0,
+            ticks_until_boost: BOOST_INTERVAL,
+            tasks: Tasks::new(initial_task),
+            queues: Default::default(),
         }
     }
 
-    pub fn thank_you_next(&mut self) {
+    /// The level of the most important ready task.
+    fn highest_ready_level(&self) -> Option<usize> {
+        self.queues.iter().position(|queue| !queue.is_empty())
+    }
+
+    fn current_level(&self) -> usize {
+        self.tasks.current.borrow().level
+    }
+
+    fn next_task(&mut self) {
         self.current_task_ticks = 0;
-        self.next_task(false);
+        let next_task = self
+            .highest_ready_level()
+            .and_then(|level| self.queues[level].pop_front());
+
+        let queues = &mut self.queues;
+        self.tasks.switch_to(next_task, |task| {
+            let level = task.level;
+            queues[level].push_back(task);
+        });
+    }
+
+    fn boost(&mut self) {
+        self.ticks_until_boost = BOOST_INTERVAL;
+
+        let mut ready = Vec::new();
+        for queue in self.queues.iter_mut() {
+            ready.extend(queue.drain(..));
+        }
+        for mut task in ready {
+            let level = base_level(task.priority);
+            task.as_mut().set_level(level);
+            self.queues[level].push_back(task);
+        }
+
+        for task in self.tasks.blocked.iter_mut() {
+            let level = base_level(task.priority);
+            task.as_mut().set_level(level);
+        }
+
+        let mut current = self.tasks.current.borrow_mut();
+        let level = base_level(current.priority);
+        current.as_mut().set_level(level);
     }
 }
+
+impl Scheduler for FeedbackScheduler {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+
+    fn add_task(&mut self, mut task: Pin<Box<Thread>>) {
+        let level = base_level(task.priority);
+        task.as_mut().set_level(level);
+        self.queues[level].push_back(task);
+    }
+
+    fn tick(&mut self) {
+        self.ticks_until_boost -= 1;
+        if self.ticks_until_boost == 0 {
+            self.boost();
+        }
+
+        if self.tasks.idle_tick() {
+            return;
+        }
+
+        self.current_task_ticks += 1;
+        let level = self.current_level();
+        if self.current_task_ticks >= time_slice(level) {
+            let level = core::cmp::min(level + 1, LEVELS - 1);
+            self.tasks.current.borrow_mut().as_mut().set_level(level);
+            match self.highest_ready_level() {
+                Some(ready) if ready <= level => self.next_task(),
+                _ => self.current_task_ticks = 0,
+            }
+        } else if self
+            .highest_ready_level()
+            .map_or(false, |ready| ready < level)
+        {
+            self.next_task();
+        }
+    }
+
+    fn yield_now(&mut self) {
+        self.next_task();
+    }
+
+    fn block_current(&mut self) {
+        self.tasks.block_current();
+        self.next_task();
+    }
+
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(task) = self.tasks.take_blocked(id) {
+            let level = task.level;
+            self.queues[level].push_back(task);
+        }
+    }
+
+    fn thank_you_next(&mut self) {
+        debug_assert_eq!(self.tasks.current.borrow().state, ThreadState::Dead);
+        self.next_task();
+    }
+
+    fn current_task(&self) -> Option<Ref<Pin<Box<Thread>>>> {
+        self.tasks.current()
+    }
+
+    fn idle_ticks(&self) -> u64 {
+        self.tasks.idle_ticks
+    }
+
+    fn load(&self) -> usize {
+        let ready = self.queues.iter().map(|queue| queue.len()).sum::<usize>();
+        ready + self.tasks.running_load()
+    }
+
+    fn take_ready_task(&mut self) -> Option<Pin<Box<Thread>>> {
+        self.queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_back())
+    }
+
+    fn take_dead_tasks(&mut self) -> Vec<Pin<Box<Thread>>> {
+        core::mem::take(&mut self.tasks.dead)
+    }
+
+    fn for_each_task(&self, f: &mut dyn FnMut(&Thread)) {
+        self.tasks.for_each(f);
+        for task in self.queues.iter().flatten() {
+            f(task);
+        }
+    }
+}
+
+fn base_level(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+/// Time slice in ticks of the tasks on `level`.
+fn time_slice(level: usize) -> u8 {
+    2 << level
+}
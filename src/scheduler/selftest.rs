@@ -0,0 +1,46 @@
+use crate::percpu;
+use crate::scheduler;
+use crate::sync::Semaphore;
+use crate::threading;
+use crate::threading::ThreadState;
+use alloc::sync::Arc;
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Takes snapshots while a thread waits for a semaphore, until it shows that thread as blocked.
+/// The calling thread has to show up as running in every one of them.
+pub fn thread_snapshot() {
+    let semaphore = Arc::new(Semaphore::new(0));
+    let waiter_semaphore = semaphore.clone();
+    let waiter = threading::spawn("selftest-waiter", move || waiter_semaphore.acquire());
+    let current = without_interrupts(|| unsafe { (*percpu::current_thread()).id });
+
+    let mut blocked = false;
+    for _ in 0..100 {
+        let tasks = scheduler::snapshot();
+        let state = |id| {
+            tasks
+                .iter()
+                .find(|task| task.id == id)
+                .map(|task| task.state)
+        };
+        assert_eq!(
+            state(current),
+            Some(ThreadState::Running),
+            "The snapshot doesn't show the calling thread running"
+        );
+
+        if state(waiter.id()) == Some(ThreadState::Blocked) {
+            blocked = true;
+            break;
+        }
+        threading::sleep(Duration::from_millis(10));
+    }
+    assert!(
+        blocked,
+        "The snapshot never showed the waiting thread blocked"
+    );
+
+    semaphore.release();
+    waiter.join().unwrap();
+}
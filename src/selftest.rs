@@ -0,0 +1,72 @@
+use crate::debug;
+use crate::fpu;
+use crate::memory;
+use crate::percpu;
+use crate::scheduler;
+use crate::sync;
+use crate::threading;
+use crate::threading::JoinHandle;
+use alloc::vec::Vec;
+
+/// Threads the stress tests run at the same time.
+pub const CONTENDERS: usize = 4;
+
+/// Every self-test by name. A test panics if it fails and lives next to the code it tests.
+const TESTS: &[(&str, fn())] = &[
+    ("copy-on-write", memory::selftest::copy_on_write),
+    ("memory syscalls", memory::selftest::memory_syscalls),
+    ("kernel pages", memory::selftest::kernel_pages),
+    ("join threads", threading::selftest::join_threads),
+    ("sleep", threading::selftest::sleep),
+    ("mutex", sync::selftest::mutex),
+    ("semaphore", sync::selftest::semaphore),
+    ("condvar", sync::selftest::condvar),
+    ("reap threads", threading::selftest::reap_threads),
+    ("extended state", fpu::selftest::extended_state),
+    ("thread locals", threading::selftest::thread_locals),
+    ("preemption", percpu::selftest::preemption),
+    ("thread snapshot", scheduler::selftest::thread_snapshot),
+];
+
+/// Checks kernel features at boot that can't be exercised from the shell yet.
+/// Panics if one of them misbehaves, after all of them ran.
+pub fn run() {
+    // Each test runs in a thread of its own, so a failing one doesn't keep the others from running.
+    let failed = TESTS
+        .iter()
+        .filter(|&&(name, test)| threading::spawn(name, test).join().is_err())
+        .map(|&(name, _)| name)
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        panic!(
+            "{} of {} self-tests failed: {}",
+            failed.len(),
+            TESTS.len(),
+            failed.join(", ")
+        );
+    }
+    debug!("All {} self-tests passed", TESTS.len());
+}
+
+/// Starts `CONTENDERS` threads that run `f` at the same time, each with its own index.
+pub fn spawn_contenders<F, T>(name: &str, f: F) -> Vec<JoinHandle<T>>
+where
+    F: Fn(usize) -> T + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    (0..CONTENDERS)
+        .map(|index| {
+            let f = f.clone();
+            threading::spawn(name, move || f(index))
+        })
+        .collect()
+}
+
+/// Waits for all `threads` and returns what they returned, in the same order.
+pub fn join_all<T>(threads: Vec<JoinHandle<T>>) -> Vec<T> {
+    threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect()
+}
@@ -0,0 +1,258 @@
+use crate::acpi;
+use crate::apic;
+use crate::debug;
+use crate::fpu;
+use crate::gdt;
+use crate::interrupts;
+use crate::memory;
+use crate::percpu;
+use crate::scheduler;
+use crate::threading;
+use crate::threading::Thread;
+use alloc::boxed::Box;
+use alloc::format;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// CPUs beyond this are left alone.
+pub const MAX_CPUS: usize = 16;
+
+/// CPUs are numbered in the order they came online, the bootstrap processor is CPU 0.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+const UNKNOWN_CPU: AtomicU8 = AtomicU8::new(0);
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [UNKNOWN_CPU; MAX_CPUS];
+
+/// A set of CPUs, bit `n` standing for CPU `n`.
+pub type CpuSet = u64;
+pub const ALL_CPUS: CpuSet = !0;
+
+/// The CPUs that take part in TLB shootdowns, see `tlb_shootdown`.
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(1);
+
+/// Every CPU's TLB flushes others asked for, and the ones it did. A CPU that wants another one to
+/// flush takes the next number and waits until the other one flushed at least that often.
+const NO_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static TLB_FLUSHES_REQUESTED: [AtomicU64; MAX_CPUS] = [NO_FLUSHES; MAX_CPUS];
+static TLB_FLUSHES_DONE: [AtomicU64; MAX_CPUS] = [NO_FLUSHES; MAX_CPUS];
+
+/// The thread the application processor that is starting up runs as, until it exits into its
+/// scheduler. Processors are started one at a time.
+static mut AP_INITIAL_TASK: Option<Pin<Box<Thread>>> = None;
+
+global_asm!(include_str!("asm/trampoline.s"));
+
+extern "C" {
+    // Defined in asm/trampoline.s
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_base: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+/// Finds the other CPUs in the MADT and starts them, one after the other. Each of them gets its
+/// own scheduler and takes threads from the others once it is online.
+///
+/// Without an RSDP or a MADT, the kernel keeps running on the bootstrap processor alone.
+pub fn init(rsdp_addr: Option<PhysAddr>) {
+    let madt = match rsdp_addr.map(acpi::parse_madt) {
+        Some(Ok(madt)) => madt,
+        Some(Err(error)) => {
+            debug!("Can't read the MADT ({:?}), using a single CPU", error);
+            return;
+        }
+        None => {
+            debug!("The bootloader found no RSDP, using a single CPU");
+            return;
+        }
+    };
+
+    apic::init(madt.local_apic_address);
+    let bsp_id = apic::id();
+    register_cpu(0, bsp_id);
+    apic::calibrate_timer();
+
+    let frame = match memory::ap_startup_frame() {
+        Some(frame) => frame,
+        None => {
+            debug!("No memory below 1 MiB for the startup code, using a single CPU");
+            return;
+        }
+    };
+    let l4_frame = memory::kernel_address_space().l4_frame();
+    if l4_frame.start_address().as_u64() > u32::MAX as u64 {
+        debug!("The kernel's page tables lie above 4 GiB, using a single CPU");
+        return;
+    }
+
+    unsafe { install_trampoline(frame, l4_frame) };
+    // The trampoline keeps running at the same address when it turns on paging.
+    memory::identity_map(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    for &apic_id in madt.apic_ids.iter() {
+        if apic_id == bsp_id {
+            continue;
+        }
+
+        let cpu = cpu_count();
+        if cpu == MAX_CPUS {
+            debug!("Ignoring the CPUs beyond the first {}", MAX_CPUS);
+            break;
+        }
+
+        if !start_ap(frame, cpu, apic_id) {
+            // It may still come up later, so the trampoline can't be reused.
+            debug!(
+                "CPU with APIC ID {} didn't come up, not starting any others",
+                apic_id
+            );
+            break;
+        }
+    }
+
+    memory::remove_identity_mapping(frame);
+    debug!("{} CPUs online", cpu_count());
+}
+
+/// Number of CPUs that are online. They have the indices `0..cpu_count()`.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Makes the CPU `cpu` look at its scheduler's inbox.
+pub fn send_reschedule(cpu: usize) {
+    let apic_id = APIC_IDS[cpu].load(Ordering::Relaxed);
+    apic::send_interrupt(apic_id, interrupts::InterruptIndex::Reschedule.as_u8());
+}
+
+/// Makes the CPUs in `cpus` flush their whole TLB, the calling one included, and waits until
+/// all of them did. Called after a mapping was removed or made more restrictive, before the
+/// frame is freed or the caller relies on the new flags.
+///
+/// Waiting is fine with interrupts disabled. CPUs flush for each other while they wait, and
+/// while they spin for a `SpinLock`, so the CPU we wait for can't be stuck waiting for us.
+pub fn tlb_shootdown(cpus: CpuSet) {
+    let cpus = cpus & ONLINE_CPUS.load(Ordering::SeqCst);
+    let this_cpu = percpu::cpu_id();
+
+    let mut tickets = [0; MAX_CPUS];
+    for cpu in (0..MAX_CPUS).filter(|cpu| cpus & 1 << cpu != 0) {
+        tickets[cpu] = TLB_FLUSHES_REQUESTED[cpu].fetch_add(1, Ordering::SeqCst) + 1;
+        // If we moved to another CPU in the meantime, the old one just flushes early.
+        if cpu != this_cpu {
+            let apic_id = APIC_IDS[cpu].load(Ordering::Relaxed);
+            apic::send_interrupt(apic_id, interrupts::InterruptIndex::TlbShootdown.as_u8());
+        }
+    }
+
+    for cpu in (0..MAX_CPUS).filter(|cpu| cpus & 1 << cpu != 0) {
+        while TLB_FLUSHES_DONE[cpu].load(Ordering::Acquire) < tickets[cpu] {
+            handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Flushes the TLB of the calling CPU if another one asked for it. Called by the interrupt
+/// handler, and by everything that waits with interrupts disabled.
+pub fn handle_tlb_shootdown() {
+    // An application processor takes locks before it has its per-CPU data. Nobody waits for it
+    // to flush until it is online.
+    if !percpu::is_installed() {
+        return;
+    }
+
+    without_interrupts(|| {
+        let cpu = percpu::cpu_id();
+        let requested = TLB_FLUSHES_REQUESTED[cpu].load(Ordering::Acquire);
+        if TLB_FLUSHES_DONE[cpu].load(Ordering::Relaxed) < requested {
+            tlb::flush_all();
+            TLB_FLUSHES_DONE[cpu].store(requested, Ordering::Release);
+        }
+    });
+}
+
+fn register_cpu(cpu: usize, apic_id: u8) {
+    APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+}
+
+/// Copies the startup code to `frame` and fills in the fields every processor uses.
+unsafe fn install_trampoline(frame: PhysFrame, l4_frame: PhysFrame) {
+    let start = &ap_trampoline as *const u8;
+    let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+    let trampoline = memory::physical_to_virtual(frame.start_address());
+    core::ptr::copy_nonoverlapping(start, trampoline.as_mut_ptr::<u8>(), length);
+
+    *trampoline_field(trampoline, &ap_trampoline_base) = frame.start_address().as_u64();
+    *trampoline_field(trampoline, &ap_trampoline_cr3) = l4_frame.start_address().as_u64();
+    *trampoline_field(trampoline, &ap_trampoline_entry) =
+        ap_main as extern "C" fn(usize) -> ! as u64;
+}
+
+/// The copy of the field `symbol` in the trampoline at `trampoline`.
+unsafe fn trampoline_field(trampoline: VirtAddr, symbol: &u8) -> *mut u64 {
+    let offset = symbol as *const u8 as u64 - &ap_trampoline as *const u8 as u64;
+    (trampoline + offset).as_mut_ptr()
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to the processor with `apic_id` and waits until it runs its
+/// scheduler as CPU `cpu`.
+fn start_ap(frame: PhysFrame, cpu: usize, apic_id: u8) -> bool {
+    let initial_task = threading::build(&format!("ap-{}", cpu), || {});
+    let stack = initial_task.stack.expect("Built a thread without a stack");
+    register_cpu(cpu, apic_id);
+
+    unsafe {
+        let trampoline = memory::physical_to_virtual(frame.start_address());
+        // The processor starts out on the initial thread's stack, so the reaper frees it once
+        // the processor switched away from it for good.
+        *trampoline_field(trampoline, &ap_trampoline_stack) = stack.top().as_u64();
+        *trampoline_field(trampoline, &ap_trampoline_cpu) = cpu as u64;
+        AP_INITIAL_TASK = Some(initial_task);
+    }
+
+    let page = (frame.start_address().as_u64() / 4096) as u8;
+    apic::send_init(apic_id);
+    threading::sleep(Duration::from_millis(10));
+    // The second startup IPI is ignored if the first one got through.
+    apic::send_startup(apic_id, page);
+    threading::sleep(Duration::from_millis(1));
+    apic::send_startup(apic_id, page);
+
+    for _ in 0..100 {
+        if cpu_count() > cpu {
+            return true;
+        }
+        threading::sleep(Duration::from_millis(10));
+    }
+
+    false
+}
+
+/// Where the application processors continue once they reached long mode.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    percpu::init_ap(cpu);
+    gdt::init_ap();
+    fpu::init();
+    interrupts::init_ap();
+    // Shootdowns that come after this reach us. Whatever the TLB cached before goes now.
+    ONLINE_CPUS.fetch_or(1 << cpu, Ordering::SeqCst);
+    tlb::flush_all();
+
+    let initial_task = unsafe { AP_INITIAL_TASK.take() }.expect("No thread for the new CPU");
+    scheduler::init_ap(initial_task);
+    CPU_COUNT.fetch_add(1, Ordering::Release);
+    debug!("CPU {} (APIC ID {}) is online", cpu, apic::id());
+
+    // From here on, the idle thread and whatever threads this CPU gets take over.
+    threading::exit();
+}
@@ -0,0 +1,12 @@
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::{SpinLock, SpinLockGuard};
+pub use wait_queue::WaitQueue;
+
+mod condvar;
+mod mutex;
+pub mod selftest;
+mod semaphore;
+mod spin_lock;
+mod wait_queue;
@@ -0,0 +1,54 @@
+use super::{MutexGuard, WaitQueue};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Lets threads wait until the data protected by a `Mutex` changes.
+///
+/// Wakeups don't guarantee that the condition the thread waits for holds, so `wait` should be
+/// called in a loop that checks it, or `wait_while` should be used.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the guard's mutex and blocks until the condition variable is notified. The mutex
+    /// is locked again before this returns.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+
+        // A notification between unlocking and blocking would get lost otherwise.
+        without_interrupts(|| self.waiters.wait_unlocking(|| drop(guard)));
+
+        mutex.lock()
+    }
+
+    /// Blocks as long as `condition` returns `true` for the protected data.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
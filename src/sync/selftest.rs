@@ -0,0 +1,79 @@
+use crate::selftest::{join_all, spawn_contenders, CONTENDERS};
+use crate::sync::{Condvar, Mutex, Semaphore};
+use crate::threading;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lets several threads increment a counter, giving up the CPU while holding the lock to make
+/// sure the others have to wait for it.
+pub fn mutex() {
+    const INCREMENTS: usize = 200;
+
+    let counter = Arc::new(Mutex::new(0));
+    let contender_counter = counter.clone();
+    join_all(spawn_contenders("selftest-mutex", move |_| {
+        for _ in 0..INCREMENTS {
+            let mut counter = contender_counter.lock();
+            let value = *counter;
+            threading::yield_now();
+            *counter = value + 1;
+        }
+    }));
+    assert_eq!(*counter.lock(), CONTENDERS * INCREMENTS, "Lost an update");
+}
+
+/// Makes sure no more threads than there are permits hold the semaphore at any time.
+pub fn semaphore() {
+    const PERMITS: usize = 2;
+    const ROUNDS: usize = 50;
+
+    let semaphore = Arc::new(Semaphore::new(PERMITS));
+    let contender_semaphore = semaphore.clone();
+    let holders = Arc::new(AtomicUsize::new(0));
+    join_all(spawn_contenders("selftest-semaphore", move |_| {
+        for _ in 0..ROUNDS {
+            contender_semaphore.acquire();
+            let inside = holders.fetch_add(1, Ordering::SeqCst) + 1;
+            assert!(inside <= PERMITS, "{} threads hold the semaphore", inside);
+            threading::yield_now();
+            holders.fetch_sub(1, Ordering::SeqCst);
+            contender_semaphore.release();
+        }
+    }));
+    assert_eq!(semaphore.available_permits(), PERMITS);
+}
+
+/// Passes numbers from one producer to several consumers through a queue guarded by a mutex and
+/// a condition variable, and checks that every number arrives exactly once.
+pub fn condvar() {
+    const ITEMS: u64 = 500;
+
+    let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+    let consumer_queue = queue.clone();
+    let consumers = spawn_contenders("selftest-condvar", move |_| {
+        let (items, available) = &*consumer_queue;
+        let mut sum = 0;
+        loop {
+            let mut items = available.wait_while(items.lock(), |items| items.is_empty());
+            match items.pop_front() {
+                // Every consumer gets its own end marker.
+                Some(0) => return sum,
+                Some(item) => sum += item,
+                None => unreachable!(),
+            }
+        }
+    });
+
+    let (items, available) = &*queue;
+    for item in (1..=ITEMS).chain(core::iter::repeat(0).take(CONTENDERS)) {
+        items.lock().push_back(item);
+        available.notify_one();
+        if item % 16 == 0 {
+            threading::yield_now();
+        }
+    }
+
+    let sum = join_all(consumers).into_iter().sum::<u64>();
+    assert_eq!(sum, ITEMS * (ITEMS + 1) / 2, "Items got lost or duplicated");
+}
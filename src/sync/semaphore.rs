@@ -0,0 +1,46 @@
+use super::WaitQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// A counting semaphore. Threads block in `acquire` while no permits are left.
+#[derive(Debug)]
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until a permit is available and takes it.
+    pub fn acquire(&self) {
+        self.waiters.wait_while(|| !self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock();
+        if *permits == 0 {
+            return false;
+        }
+
+        *permits -= 1;
+        true
+    }
+
+    /// Returns a permit and wakes up one of the threads waiting for it.
+    pub fn release(&self) {
+        without_interrupts(|| {
+            *self.permits.lock() += 1;
+            self.waiters.wake_one();
+        });
+    }
+
+    pub fn available_permits(&self) -> usize {
+        *self.permits.lock()
+    }
+}
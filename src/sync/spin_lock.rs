@@ -0,0 +1,100 @@
+use crate::smp;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+/// A spin lock that keeps interrupts disabled while it is held. The holder can't be preempted,
+/// so other threads never spin for a whole time slice, and interrupt handlers on the same CPU
+/// can't deadlock with it. Only meant for short critical sections that don't block.
+#[derive(Debug)]
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                // The holder may be waiting for us to flush our TLB.
+                smp::handle_tlb_shootdown();
+                core::hint::spin_loop();
+            }
+        }
+
+        SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    /// Like `lock`, but gives up right away if the lock is held.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            return None;
+        }
+
+        Some(SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+        })
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::scheduler;
+use crate::threading::ThreadId;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Threads waiting for something, woken in the order they started to wait.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: Mutex<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks the calling thread until it is woken by `wake_one` or `wake_all`, and calls
+    /// `unlock` once the thread is queued.
+    ///
+    /// Has to be called with interrupts disabled, and whatever the thread waits for has to be
+    /// checked while holding the lock `unlock` releases. Otherwise the wakeup could happen between
+    /// the check and blocking and get lost.
+    pub fn wait_unlocking<F>(&self, unlock: F)
+    where
+        F: FnOnce(),
+    {
+        let scheduler = unsafe { scheduler::local() };
+        let current = scheduler
+            .current_task()
+            .map(|task| task.id)
+            .expect("wait_unlocking() called while switching threads");
+
+        self.waiters.lock().push(current);
+        unlock();
+        // A wakeup from another CPU only arrives once we blocked, since interrupts are off.
+        scheduler.block_current();
+    }
+
+    /// Blocks the calling thread as long as `condition` returns `true`. The condition is checked
+    /// with the queue locked, so it must not wake the queue itself.
+    pub fn wait_while<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        without_interrupts(|| loop {
+            let mut waiters = self.waiters.lock();
+            if !condition() {
+                return;
+            }
+
+            let scheduler = unsafe { scheduler::local() };
+            let current = scheduler
+                .current_task()
+                .map(|task| task.id)
+                .expect("wait_while() called while switching threads");
+            waiters.push(current);
+            drop(waiters);
+            scheduler.block_current();
+        });
+    }
+
+    /// Wakes the thread that has been waiting the longest. Returns `false` if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                return false;
+            }
+
+            let waiter = waiters.remove(0);
+            scheduler::wake(waiter);
+
+            true
+        })
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        without_interrupts(|| {
+            let waiters = core::mem::take(&mut *self.waiters.lock());
+            for waiter in waiters.iter() {
+                scheduler::wake(*waiter);
+            }
+
+            waiters.len()
+        })
+    }
+}
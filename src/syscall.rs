@@ -0,0 +1,308 @@
+use crate::debug;
+use crate::memory;
+use crate::memory::AddressSpaceError;
+use crate::scheduler;
+use crate::serial::SERIAL;
+use crate::threading;
+use crate::threading::ThreadState;
+use crate::timer;
+use crate::TERMINAL;
+use bmos_std::io::IOChannel;
+use bmos_std::mem::{
+    MemoryError, MemoryInfo, MemoryKind, MemoryMapEntry, MemoryResult, Protection,
+};
+use bmos_std::syscall::{
+    SYSCALL_MEM_INFO, SYSCALL_MEM_MAP, SYSCALL_MEM_PROTECT, SYSCALL_MEM_REGIONS,
+    SYSCALL_MEM_RESERVE, SYSCALL_MEM_UNMAP, SYSCALL_PRINT, SYSCALL_SET_FS_BASE, SYSCALL_SLEEP,
+    SYSCALL_THREADS,
+};
+use bmos_std::thread::{ThreadClock, ThreadInfo, THREAD_NAME_LENGTH};
+use bootloader::boot_info::MemoryRegionKind;
+use core::convert::TryFrom;
+use core::fmt::Write;
+use core::time::Duration;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Why the kernel refuses to write through a pointer a caller passed.
+#[derive(Debug)]
+enum UserPointerError {
+    Null,
+    Unaligned,
+    /// The memory wraps around or the caller can't hand it out, like kernel memory or a region
+    /// that isn't writable.
+    Inaccessible,
+}
+
+/// Runs syscall `number` with the arguments passed in rdi, rsi, rdx and r10.
+pub fn dispatch(number: u64, arguments: [u64; 4]) {
+    match number {
+        SYSCALL_PRINT => print(arguments),
+        SYSCALL_MEM_RESERVE | SYSCALL_MEM_MAP | SYSCALL_MEM_PROTECT | SYSCALL_MEM_UNMAP => {
+            let result = match user_ref::<MemoryResult>(arguments[3]) {
+                Ok(result) => result,
+                Err(error) => {
+                    debug!("Invalid memory result: {:?}", error);
+                    return;
+                }
+            };
+            *result = match memory_syscall(number, arguments) {
+                Ok(addr) => MemoryResult {
+                    error: 0,
+                    addr: addr.as_u64(),
+                },
+                Err(error) => MemoryResult {
+                    error: error as u64,
+                    addr: 0,
+                },
+            };
+        }
+        SYSCALL_MEM_INFO => match user_ref::<MemoryInfo>(arguments[0]) {
+            Ok(info) => *info = memory_info(),
+            Err(error) => debug!("Invalid memory info: {:?}", error),
+        },
+        SYSCALL_MEM_REGIONS => memory_map(arguments),
+        SYSCALL_SLEEP => match u32::try_from(arguments[1]) {
+            Ok(nanos) if nanos < 1_000_000_000 => {
+                threading::sleep(Duration::new(arguments[0], nanos));
+            }
+            _ => debug!("Invalid sleep nanoseconds: {}", arguments[1]),
+        },
+        SYSCALL_SET_FS_BASE => match VirtAddr::try_new(arguments[0]) {
+            Ok(address) => threading::set_fs_base(address),
+            Err(_) => debug!("Invalid FS base: {:#x}", arguments[0]),
+        },
+        SYSCALL_THREADS => thread_list(arguments),
+        _ => debug!("INVALID SYSCALL NUMBER"),
+    }
+    debug!("SYSCALL: {}", number);
+}
+
+/// Checks that the caller can hand `count` values of `T` at `addr` to the kernel to fill in and
+/// returns them as a slice. The memory has to lie in writable regions the caller's address space
+/// reserved, or in mapped parts of the kernel heap, where threads allocate their own values since
+/// they share it. Any other kernel memory, including the stacks, is refused.
+///
+/// An empty slice never touches `addr`.
+fn user_slice<'a, T: Copy>(addr: u64, count: u64) -> Result<&'a mut [T], UserPointerError> {
+    if count == 0 {
+        return Ok(&mut []);
+    }
+    if addr == 0 {
+        return Err(UserPointerError::Null);
+    }
+    if addr % core::mem::align_of::<T>() as u64 != 0 {
+        return Err(UserPointerError::Unaligned);
+    }
+
+    let size = count
+        .checked_mul(core::mem::size_of::<T>() as u64)
+        .ok_or(UserPointerError::Inaccessible)?;
+    let start = VirtAddr::try_new(addr).map_err(|_| UserPointerError::Inaccessible)?;
+
+    if !memory::current_address_space().is_writable(start, size)
+        && !memory::is_heap_memory(start, size)
+    {
+        return Err(UserPointerError::Inaccessible);
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), count as usize) })
+}
+
+/// Like `user_slice`, for a single value.
+fn user_ref<'a, T: Copy>(addr: u64) -> Result<&'a mut T, UserPointerError> {
+    user_slice(addr, 1).map(|values| &mut values[0])
+}
+
+fn print(arguments: [u64; 4]) {
+    let data_start = arguments[0] as *const u8;
+    let length = arguments[1];
+    let io_channel = IOChannel::from_u32(arguments[2] as u32).unwrap();
+    debug!(
+        "Arguments: data_start = {:#?}, length = {}, io_channel = {:?}",
+        data_start, length, io_channel
+    );
+
+    let data_slice = unsafe { core::slice::from_raw_parts(data_start, length as usize) };
+    let string = core::str::from_utf8(data_slice);
+    debug!("String Result: {:?}", string);
+
+    match io_channel {
+        IOChannel::Stdout => unsafe {
+            TERMINAL.as_ref().unwrap().write(string.unwrap());
+        },
+        IOChannel::Serial => {
+            let mut serial = SERIAL.lock();
+            serial.write_str(string.unwrap()).unwrap();
+        }
+    }
+}
+
+/// Reserves, maps, protects or unmaps memory in the caller's address space.
+/// Returns the start of the affected range.
+fn memory_syscall(number: u64, arguments: [u64; 4]) -> Result<VirtAddr, MemoryError> {
+    let [addr, size, protection, _] = arguments;
+    let addr = VirtAddr::try_new(addr).map_err(|_| MemoryError::OutsideUserSpace)?;
+    let address_space = memory::current_address_space();
+
+    let result = match number {
+        SYSCALL_MEM_RESERVE => {
+            let flags = page_flags(protection)?;
+            if addr.is_null() {
+                address_space.reserve_anywhere(size, flags)
+            } else {
+                address_space.reserve(addr, size, flags).map(|_| addr)
+            }
+        }
+        SYSCALL_MEM_MAP => address_space.populate(addr, size).map(|_| addr),
+        SYSCALL_MEM_PROTECT => {
+            let flags = page_flags(protection)?;
+            address_space.protect(addr, size, flags).map(|_| addr)
+        }
+        SYSCALL_MEM_UNMAP => address_space.release(addr, size).map(|_| addr),
+        _ => unreachable!(),
+    };
+
+    result.map_err(|error| match error {
+        AddressSpaceError::Unaligned => MemoryError::Unaligned,
+        AddressSpaceError::OutsideUserSpace => MemoryError::OutsideUserSpace,
+        AddressSpaceError::Overlapping => MemoryError::Overlapping,
+        AddressSpaceError::NotReserved => MemoryError::NotReserved,
+        AddressSpaceError::OutOfMemory => MemoryError::OutOfMemory,
+    })
+}
+
+/// Translates `Protection` bits into the flags pages get mapped with.
+fn page_flags(bits: u64) -> Result<PageTableFlags, MemoryError> {
+    let protection = Protection::from_bits(bits).ok_or(MemoryError::InvalidProtection)?;
+
+    let mut flags = PageTableFlags::empty();
+    if protection != Protection::NONE {
+        flags |= PageTableFlags::PRESENT;
+    }
+    if protection.contains(Protection::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !protection.contains(Protection::EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    Ok(flags)
+}
+
+fn memory_info() -> MemoryInfo {
+    let stats = memory::stats();
+    let slab_size = stats
+        .slabs
+        .iter()
+        .map(|slab| slab.slabs * 4096)
+        .sum::<usize>();
+    let slab_used = stats
+        .slabs
+        .iter()
+        .map(|slab| slab.objects_in_use * slab.object_size)
+        .sum::<usize>();
+
+    MemoryInfo {
+        physical_total: stats.frames.total as u64 * 4096,
+        physical_used: stats.frames.used as u64 * 4096,
+        heap_size: stats.heap.size as u64,
+        heap_used: stats.heap.used as u64,
+        heap_limit: stats.heap.limit as u64,
+        slab_size: slab_size as u64,
+        slab_used: slab_used as u64,
+        kernel_pages_size: stats.kernel_pages.size,
+        kernel_pages_used: stats.kernel_pages.used,
+        kernel_stacks_size: stats.kernel_stacks.size,
+        kernel_stacks_used: stats.kernel_stacks.used,
+    }
+}
+
+/// Copies the memory map into the caller's buffer at rdi, which holds rsi entries, and stores
+/// the number of entries in the map at rdx.
+fn memory_map(arguments: [u64; 4]) {
+    let regions = memory::memory_map();
+    let entries = match user_slice::<MemoryMapEntry>(arguments[0], arguments[1]) {
+        Ok(entries) => entries,
+        Err(error) => {
+            debug!("Invalid memory map buffer: {:?}", error);
+            return;
+        }
+    };
+
+    for (entry, region) in entries.iter_mut().zip(regions.iter()) {
+        let (kind, firmware_kind) = match region.kind {
+            MemoryRegionKind::Usable => (MemoryKind::Usable, 0),
+            MemoryRegionKind::Bootloader => (MemoryKind::Bootloader, 0),
+            MemoryRegionKind::UnknownUefi(kind) => (MemoryKind::Uefi, kind),
+            MemoryRegionKind::UnknownBios(kind) => (MemoryKind::Bios, kind),
+            _ => (MemoryKind::Unknown, 0),
+        };
+
+        *entry = MemoryMapEntry {
+            start: region.start,
+            end: region.end,
+            kind: kind as u32,
+            firmware_kind,
+        };
+    }
+
+    match user_ref::<u64>(arguments[2]) {
+        Ok(count) => *count = regions.len() as u64,
+        Err(error) => debug!("Invalid memory map count: {:?}", error),
+    }
+}
+
+/// Copies a snapshot of all threads into the caller's buffer at rdi, which holds rsi entries, and
+/// stores the number of threads at rdx and the timer's `ThreadClock` at r10.
+fn thread_list(arguments: [u64; 4]) {
+    let entries = match user_slice::<ThreadInfo>(arguments[0], arguments[1]) {
+        Ok(entries) => entries,
+        Err(error) => {
+            debug!("Invalid thread list buffer: {:?}", error);
+            return;
+        }
+    };
+    let tasks = scheduler::snapshot();
+    let ticks = timer::ticks();
+
+    for (entry, task) in entries.iter_mut().zip(tasks.iter()) {
+        let mut name = [0; THREAD_NAME_LENGTH];
+        let mut length = core::cmp::min(task.name.len(), THREAD_NAME_LENGTH);
+        while !task.name.is_char_boundary(length) {
+            length -= 1;
+        }
+        name[..length].copy_from_slice(&task.name.as_bytes()[..length]);
+
+        let state = match task.state {
+            ThreadState::Ready => bmos_std::thread::ThreadState::Ready,
+            ThreadState::Running => bmos_std::thread::ThreadState::Running,
+            ThreadState::Blocked => bmos_std::thread::ThreadState::Blocked,
+            ThreadState::Dead => bmos_std::thread::ThreadState::Dead,
+        };
+
+        *entry = ThreadInfo {
+            id: task.id.as_u64(),
+            name,
+            state: state as u32,
+            cpu: task.cpu as u32,
+            ticks: task.ticks,
+            context_switches: task.context_switches,
+            created_at: task.created_at,
+        };
+    }
+
+    match user_ref::<u64>(arguments[2]) {
+        Ok(count) => *count = tasks.len() as u64,
+        Err(error) => debug!("Invalid thread count: {:?}", error),
+    }
+    match user_ref::<ThreadClock>(arguments[3]) {
+        Ok(clock) => {
+            *clock = ThreadClock {
+                ticks,
+                ticks_per_second: timer::TICKS_PER_SECOND,
+            }
+        }
+        Err(error) => debug!("Invalid thread list clock: {:?}", error),
+    }
+}
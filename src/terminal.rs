@@ -77,29 +77,41 @@ impl<'a> Terminal<'a> {
         (*lock).clone().into_inner()
     }
 
-    fn move_cursor_down(&self) -> Position {
+    /// Moves the cursor to the start of the next line, scrolling if it is on the last one.
+    fn new_line(&self) -> Position {
         let lock = self.cursor.lock();
         let mut cursor = lock.borrow_mut();
         if cursor.row == self.console.height() - 1 {
-            drop(cursor);
-            return (*lock).clone().into_inner();
+            self.console.scroll_up();
+        } else {
+            cursor.row += 1;
         }
 
-        cursor.row += 1;
+        cursor.column = 0;
 
         drop(cursor);
         (*lock).clone().into_inner()
     }
 
-    fn move_cursor_to_start(&self) -> Position {
-        let lock = self.cursor.lock();
-        let mut cursor = lock.borrow_mut();
-
-        cursor.column = 0;
+    /// Prints `string` at the cursor and moves the cursor behind it. Lines that don't fit
+    /// are wrapped.
+    pub fn write(&self, string: &str) {
+        for c in string.chars() {
+            if c == '\n' {
+                self.new_line();
+                continue;
+            }
 
-        drop(cursor);
+            let cursor = self.cursor_position();
+            self.console.put_char(c, cursor.column, cursor.row);
+            if cursor.column == self.console.width() - 1 {
+                self.new_line();
+            } else {
+                self.move_cursor_right();
+            }
+        }
 
-        (*lock).clone().into_inner()
+        self.console.redraw_screen(self.cursor_position());
     }
 
     pub fn cursor_position(&self) -> Position {
@@ -143,9 +155,6 @@ impl<'a> Terminal<'a> {
             return;
         }
 
-        self.move_cursor_down();
-        self.move_cursor_to_start();
-
         if let Some(shell) = &self.shell {
             shell.process_input(string);
         }
@@ -204,18 +213,15 @@ impl<'a> KeyboardHandler for Terminal<'a> {
                 input_buffer.clear();
                 debug!("User Input: {:?}", &input);
 
+                drop(input_buffer);
+
+                self.new_line();
                 self.handle_input(input);
 
-                let cursor_lock = self.cursor.lock();
-                let cursor = cursor_lock.borrow();
-                // Print a new prompt
-                let new_cursor_position = Position {
-                    column: 0,
-                    row: cursor.row + 1,
-                };
-                drop(cursor);
-                drop(cursor_lock);
-                self.set_cursor_position(new_cursor_position);
+                // Print a new prompt, on a line of its own if the command left some output on the current one
+                if self.cursor_position().column != 0 {
+                    self.new_line();
+                }
                 self.draw_prompt();
 
                 return;
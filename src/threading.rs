@@ -1,119 +1,482 @@
 use crate::debug;
+use crate::fpu::ExtendedState;
 use crate::memory;
-use crate::SCHEDULER;
+use crate::memory::{AddressSpace, KernelStack};
+use crate::percpu;
+use crate::scheduler;
+use crate::sync::WaitQueue;
+use crate::timer;
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::sync::Arc;
 use core::ffi::c_void;
+use core::fmt;
 use core::marker::PhantomPinned;
 use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::FsBase;
 use x86_64::VirtAddr;
 
-/// Each thread has its own stack. The stack is exactly one page of memory, which equals 4KiB.
+use join::JoinState;
+pub use join::{JoinError, JoinHandle, PanicMessage};
+
+mod join;
+pub mod selftest;
+
+/// Each thread has its own stack of `DEFAULT_STACK_SIZE` bytes, unless it was built with a
+/// different size, with an unmapped guard page right below it. Only the boot thread keeps running
+/// on the stack the bootloader set up.
 #[repr(C)]
 #[derive(Debug)]
 pub struct Thread {
     /// The stack pointer represents the thread's stack state before switching to another one.
     /// It is the first field so we can re-use the pointer to this struct as a pointer to the stack pointer.
     pub stack_pointer: VirtAddr,
+    /// Saved and restored by `__switch_context`, which expects it right after the stack pointer.
+    pub extended_state: ExtendedState,
     pub entry: *mut c_void,
+    pub id: ThreadId,
     pub name: String,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The run queue the feedback scheduler keeps the thread in, starting at its priority's.
+    pub level: usize,
+    /// Timer ticks during which the thread was running.
+    pub ticks: u64,
+    /// How often a scheduler switched to the thread.
+    pub context_switches: u64,
+    /// `timer::ticks()` when the thread was built.
+    pub created_at: u64,
+    /// `None` for the boot thread, whose stack the kernel didn't allocate.
+    pub stack: Option<KernelStack>,
+    /// Loaded into CR3 whenever the scheduler switches to this thread.
+    pub address_space: Arc<AddressSpace>,
+    /// Loaded into the FS base register whenever the scheduler switches to this thread. It points
+    /// to `tls_root` unless the thread moved it with `set_fs_base`.
+    pub fs_base: VirtAddr,
+    /// The word at FS:0, where `bmos_std::thread::LocalKey` keeps the thread's table of
+    /// thread-local values. Null until the thread uses the first one.
+    tls_root: Box<u64>,
+    /// Set for threads started with `spawn`, which can be joined.
+    join_state: Option<Arc<JoinState>>,
     _marker: PhantomPinned,
 }
 
+impl Thread {
+    pub fn set_state(self: Pin<&mut Self>, state: ThreadState) {
+        // The state isn't structurally pinned, changing it doesn't move the thread.
+        unsafe {
+            self.get_unchecked_mut().state = state;
+        }
+    }
+
+    pub fn set_level(self: Pin<&mut Self>, level: usize) {
+        unsafe {
+            self.get_unchecked_mut().level = level;
+        }
+    }
+
+    /// Called by the scheduler whenever it switches to the thread.
+    pub fn count_switch(self: Pin<&mut Self>) {
+        unsafe {
+            self.get_unchecked_mut().context_switches += 1;
+        }
+    }
+}
+
+// Threads move between CPUs. `entry` is only used by the thread itself, when it starts.
+unsafe impl Send for Thread {}
+
 impl Eq for Thread {}
 impl PartialEq for Thread {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.id == other.id
     }
 }
 
 impl Drop for Thread {
     fn drop(&mut self) {
-        debug!("Dropping {}", self.name);
+        if let Some(stack) = self.stack {
+            memory::free_kernel_stack(stack);
+        }
+    }
+}
+
+/// Uniquely identifies a thread. IDs are handed out in increasing order and never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
     }
 }
 
-unsafe fn initialize_stack(base_addr: VirtAddr, thread: &Thread, runnable: *const c_void) {
-    let base_ptr = base_addr.as_mut_ptr::<u64>();
-    let slice = unsafe { core::slice::from_raw_parts_mut(base_ptr, 4096 / 8) };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in the run queue.
+    Ready,
+    /// Currently executing.
+    Running,
+    /// Waiting for something else to wake it up, it isn't scheduled until then.
+    Blocked,
+    /// Finished, waiting to be cleaned up.
+    Dead,
+}
+
+/// How urgently a thread wants to run. Schedulers without priorities ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Interactive threads that mostly wait for input.
+    High,
+    Normal,
+    /// Background work that may wait until nothing else wants to run.
+    Low,
+}
+
+/// Stack size of threads that don't ask for a specific one.
+pub const DEFAULT_STACK_SIZE: usize = 32 * 1024;
+
+/// Lays out a fresh stack the way `__switch_context` expects it when it switches to the thread
+/// for the first time and returns the thread's initial stack pointer.
+///
+/// From the top, the stack holds an empty slot that keeps `thread_start` 16-byte aligned, the
+/// address of `thread_start` for `__switch_context` to return to and zeroes for the six
+/// callee-saved registers it pops.
+unsafe fn initialize_stack(stack: &KernelStack) -> VirtAddr {
+    let base_ptr = stack.bottom().as_mut_ptr::<u64>();
+    let slice = unsafe { core::slice::from_raw_parts_mut(base_ptr, stack.size() as usize / 8) };
     slice.fill(0);
     unsafe {
-        slice[slice.len() - 1] =
+        slice[slice.len() - 2] =
             thread_start as *const extern "C" fn(*mut Thread) -> *mut c_void as u64;
     }
 
     extern "C" fn thread_start(thread: *mut Thread) -> *mut c_void {
+        // The scheduler switched to us with interrupts disabled.
+        interrupts::enable();
+
         unsafe {
-            debug!("thread_start(): {}", (*thread).name);
+            debug!("thread_start(): {} ({})", (*thread).name, (*thread).id);
 
             Box::from_raw((*thread).entry as *mut Box<dyn FnOnce()>)();
 
             debug!("Closure ended");
+            bmos_std::thread::drop_thread_locals();
             cleanup_thread(thread);
         }
 
         core::ptr::null_mut()
     }
+
+    stack.top() - (8 * 8) as u64
 }
 
-pub(crate) fn build<F>(name: &str, f: F) -> Pin<Box<Thread>>
-where
-    F: FnOnce() -> (),
-    F: Send + 'static,
-{
-    let stack_page = memory::allocate_kernel_page().expect("Failed to allocate kernel memory");
-    let stack_addr = stack_page.start_address() + stack_page.size() - 1u64;
-
-    // Weird hack to get the raw pointer to the closure/function
-    let boxed_closure: Box<dyn FnOnce()> = Box::new(f);
-    let pointer = Box::into_raw(Box::new(boxed_closure));
-
-    let thread = Thread {
-        name: name.to_string(),
-        entry: pointer as *mut c_void,
-        stack_pointer: stack_addr - (7 * 8) as u64,
-        _marker: PhantomPinned,
-    };
+/// Configures a new thread before it is built or spawned.
+pub struct Builder {
+    name: String,
+    stack_size: usize,
+    priority: Priority,
+    address_space: Option<Arc<AddressSpace>>,
+}
 
-    debug!(
-        "Built new thread '{}' with stack base at {:x?}",
-        thread.name.as_str(),
-        stack_addr,
-    );
-    debug!("Thread: {:?}", thread);
+impl Builder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            stack_size: DEFAULT_STACK_SIZE,
+            priority: Priority::Normal,
+            address_space: None,
+        }
+    }
 
-    unsafe {
-        initialize_stack(
-            stack_page.start_address(),
-            &thread,
-            pointer as *const c_void,
+    /// Sets the size of the thread's stack in bytes. It is rounded up to whole pages.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Runs the thread in `address_space` instead of the kernel's address space.
+    pub fn address_space(mut self, address_space: Arc<AddressSpace>) -> Self {
+        self.address_space = Some(address_space);
+        self
+    }
+
+    pub fn build<F>(self, f: F) -> Pin<Box<Thread>>
+    where
+        F: FnOnce() -> (),
+        F: Send + 'static,
+    {
+        let stack = memory::allocate_kernel_stack(self.stack_size)
+            .expect("Failed to allocate kernel memory");
+
+        // Weird hack to get the raw pointer to the closure/function
+        let boxed_closure: Box<dyn FnOnce()> = Box::new(f);
+        let pointer = Box::into_raw(Box::new(boxed_closure));
+
+        let stack_pointer = unsafe { initialize_stack(&stack) };
+        let thread = self.thread(pointer as *mut c_void, Some(stack), stack_pointer);
+
+        debug!(
+            "Built new thread '{}' ({}) with a {} KiB stack at {:x?}",
+            thread.name.as_str(),
+            thread.id,
+            stack.size() / 1024,
+            stack.top(),
+        );
+        debug!("Thread: {:?}", thread);
+
+        let boxed = Box::pin(thread);
+
+        debug!("Thread addr: {:x?}", &*boxed);
+
+        boxed
+    }
+
+    /// Builds the thread that stands for the code running right now, on the stack the bootloader
+    /// set up. The stack size is ignored, since no stack is allocated.
+    fn build_boot(self) -> Pin<Box<Thread>> {
+        // The stack pointer is saved when the scheduler switches away for the first time.
+        let thread = self.thread(ptr::null_mut(), None, VirtAddr::zero());
+        debug!(
+            "Built boot thread '{}' ({})",
+            thread.name.as_str(),
+            thread.id
         );
+
+        Box::pin(thread)
+    }
+
+    fn thread(
+        self,
+        entry: *mut c_void,
+        stack: Option<KernelStack>,
+        stack_pointer: VirtAddr,
+    ) -> Thread {
+        let tls_root = Box::new(0);
+        Thread {
+            name: self.name,
+            entry,
+            extended_state: ExtendedState::new(),
+            id: ThreadId::next(),
+            state: ThreadState::Ready,
+            priority: self.priority,
+            level: 0,
+            ticks: 0,
+            context_switches: 0,
+            created_at: timer::ticks(),
+            stack_pointer,
+            stack,
+            address_space: self
+                .address_space
+                .unwrap_or_else(memory::kernel_address_space),
+            fs_base: VirtAddr::from_ptr(&*tls_root),
+            tls_root,
+            join_state: None,
+            _marker: PhantomPinned,
+        }
     }
 
-    let boxed = Box::pin(thread);
+    /// Starts the thread. Its return value, or its panic, can be collected with the returned
+    /// handle.
+    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T,
+        F: Send + 'static,
+        T: Send + 'static,
+    {
+        let result = Arc::new(Mutex::new(None));
+        let thread_result = result.clone();
+        let mut task = self.build(move || {
+            *thread_result.lock() = Some(f());
+        });
+
+        let state = Arc::new(JoinState::default());
+        // The join state isn't structurally pinned, setting it doesn't move the thread.
+        unsafe {
+            task.as_mut().get_unchecked_mut().join_state = Some(state.clone());
+        }
+        let handle = JoinHandle::new(task.id, state, result);
 
-    debug!("Thread addr: {:x?}", &*boxed);
+        scheduler::add_task(task);
 
-    boxed
+        handle
+    }
 }
 
-pub(crate) fn spawn<F>(name: &str, f: F)
+pub(crate) fn build<F>(name: &str, f: F) -> Pin<Box<Thread>>
 where
     F: FnOnce() -> (),
     F: Send + 'static,
 {
-    let task = build(name, f);
+    Builder::new(name).build(f)
+}
+
+pub(crate) fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T,
+    F: Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new(name).spawn(f)
+}
+
+/// Builds the thread that stands for the code the bootstrap processor is running right now.
+pub(crate) fn build_boot() -> Pin<Box<Thread>> {
+    Builder::new("main").build_boot()
+}
+
+/// Builds the thread the scheduler runs when no other thread is ready. It halts the CPU until the
+/// next interrupt and gives way as soon as that interrupt made another thread ready.
+pub(crate) fn build_idle() -> Pin<Box<Thread>> {
+    Builder::new("idle").priority(Priority::Low).build(|| loop {
+        interrupts::enable_and_hlt();
+        yield_now();
+    })
+}
+
+/// Wakes up the reaper whenever a thread died.
+static REAPER_WAITERS: WaitQueue = WaitQueue::new();
 
+/// Starts the thread that frees dead threads. A thread can't free its own stack, since it runs on
+/// that stack until the very end.
+pub(crate) fn start_reaper() {
+    spawn("reaper", || loop {
+        REAPER_WAITERS.wait_while(|| !scheduler::has_dead_tasks());
+        let dead_threads = scheduler::take_dead_tasks();
+
+        for thread in dead_threads {
+            debug!(
+                "Reaping thread '{}' ({}), freeing its {} KiB stack",
+                thread.name,
+                thread.id,
+                thread.stack.map_or(0, |stack| stack.size()) / 1024
+            );
+            drop(thread);
+        }
+    });
+}
+
+/// Called by the schedulers when they hand over threads that died.
+pub(crate) fn wake_reaper() {
+    REAPER_WAITERS.wake_one();
+}
+
+/// Blocks the calling thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let ticks = core::cmp::max(timer::duration_to_ticks(duration), 1);
+
+    // The timer interrupt must not wake us before we are blocked.
+    without_interrupts(|| {
+        let scheduler = unsafe { scheduler::local() };
+        let current = scheduler
+            .current_task()
+            .map(|task| task.id)
+            .expect("sleep() called while switching threads");
+
+        timer::wake_at(timer::ticks().saturating_add(ticks), current);
+        scheduler.block_current();
+    });
+}
+
+/// Lets other threads run before the calling thread continues.
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// Points the FS base register of the calling thread at `address`. Whatever is there replaces
+/// the thread-local storage the thread started out with.
+pub fn set_fs_base(address: VirtAddr) {
+    without_interrupts(|| {
+        unsafe { (*percpu::current_thread()).fs_base = address };
+        FsBase::write(address);
+    });
+}
+
+/// Terminates the calling thread. Its thread-local values are leaked unless it returns from its
+/// function instead.
+pub fn exit() -> ! {
     unsafe {
-        SCHEDULER.as_mut().unwrap().add_task(task);
+        interrupts::disable();
+        cleanup_thread(percpu::current_thread());
     }
+
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Called by the panic handler. Terminates the panicking thread and hands `message` to whoever
+/// joins it. Returns if the panic can't be pinned on a joinable thread, the kernel has to halt then.
+pub fn handle_panic(message: PanicMessage) {
+    // The panicking thread must not move to another CPU while we look at it.
+    interrupts::disable();
+    let scheduler = match unsafe { scheduler::try_local() } {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+    let join_state = match scheduler.current_task() {
+        Some(task) => match &task.join_state {
+            Some(join_state) => join_state.clone(),
+            None => return,
+        },
+        None => return,
+    };
+
+    join_state.set_panic_message(message);
+    exit();
+}
+
+/// Terminates the calling thread as if it panicked with `message`, but without halting the
+/// kernel if nobody can join it.
+pub fn terminate(message: PanicMessage) -> ! {
+    handle_panic(message);
+    exit();
 }
 
 pub unsafe fn cleanup_thread(current_thread: *mut Thread) {
-    debug!("Cleaning up thread {}", (*current_thread).name);
+    // We never return, so interrupts stay off until the next thread runs. Otherwise we could be
+    // preempted after our joiner or the reaper think we are gone.
+    interrupts::disable();
+
+    debug!(
+        "Cleaning up thread {} ({})",
+        (*current_thread).name,
+        (*current_thread).id
+    );
+    (*current_thread).state = ThreadState::Dead;
+    if let Some(join_state) = &(*current_thread).join_state {
+        join_state.finish();
+    }
+
+    // Leave the thread's address space, so it gets torn down once no other thread uses it.
+    let kernel_address_space = memory::kernel_address_space();
+    kernel_address_space.activate();
+    (*current_thread).address_space = kernel_address_space;
 
-    SCHEDULER.as_mut().unwrap().thank_you_next();
+    // The scheduler hands us to the reaper once it switched away from our stack.
+    scheduler::local().thank_you_next();
 }
 
 global_asm!(include_str!("asm/threads.s"));
@@ -0,0 +1,134 @@
+use super::ThreadId;
+use crate::scheduler;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Debug)]
+pub enum JoinError {
+    /// The thread panicked, this is the panic message.
+    Panicked(String),
+}
+
+/// Longer panic messages are cut off.
+const PANIC_MESSAGE_LENGTH: usize = 256;
+
+/// A panic message, formatted without allocating. The panic may have happened inside the
+/// allocator, or while one of its locks was held.
+pub struct PanicMessage {
+    bytes: [u8; PANIC_MESSAGE_LENGTH],
+    length: usize,
+}
+
+impl PanicMessage {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; PANIC_MESSAGE_LENGTH],
+            length: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // `write_str` only ever cuts at character boundaries.
+        core::str::from_utf8(&self.bytes[..self.length]).unwrap()
+    }
+}
+
+impl fmt::Write for PanicMessage {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let mut length = core::cmp::min(string.len(), PANIC_MESSAGE_LENGTH - self.length);
+        while !string.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        self.bytes[self.length..self.length + length].copy_from_slice(&string.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PanicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// What a thread shares with whoever may join it, regardless of its return type.
+#[derive(Debug, Default)]
+pub struct JoinState {
+    finished: AtomicBool,
+    panic_message: Mutex<Option<PanicMessage>>,
+    /// The thread blocked in `join`, if any.
+    waiter: Mutex<Option<ThreadId>>,
+}
+
+impl JoinState {
+    pub fn set_panic_message(&self, message: PanicMessage) {
+        *self.panic_message.lock() = Some(message);
+    }
+
+    /// Marks the thread as finished and wakes up the joiner.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        if let Some(waiter) = self.waiter.lock().take() {
+            scheduler::wake(waiter);
+        }
+    }
+}
+
+/// An owned permission to wait for a thread to finish and take its return value.
+/// Dropping the handle detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    state: Arc<JoinState>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(id: ThreadId, state: Arc<JoinState>, result: Arc<Mutex<Option<T>>>) -> Self {
+        Self { id, state, result }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread finished and returns its return value, or the panic message if
+    /// it panicked.
+    pub fn join(self) -> Result<T, JoinError> {
+        // `finish` takes the waiter after setting `finished`, so checking it with the waiter
+        // locked makes sure the wakeup can't get lost. A wakeup from another CPU only arrives
+        // once we blocked, since interrupts are off.
+        without_interrupts(|| {
+            let mut waiter = self.state.waiter.lock();
+            if self.state.finished.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let scheduler = unsafe { scheduler::local() };
+            let current = scheduler
+                .current_task()
+                .map(|task| task.id)
+                .expect("join() called while switching threads");
+            assert_ne!(current, self.id, "A thread can't join itself");
+
+            *waiter = Some(current);
+            drop(waiter);
+            scheduler.block_current();
+        });
+
+        if let Some(message) = self.state.panic_message.lock().take() {
+            return Err(JoinError::Panicked(message.as_str().to_string()));
+        }
+
+        Ok(self
+            .result
+            .lock()
+            .take()
+            .expect("Thread finished without a result"))
+    }
+}
@@ -0,0 +1,108 @@
+use crate::memory;
+use crate::scheduler;
+use crate::selftest::{join_all, spawn_contenders, CONTENDERS};
+use crate::threading;
+use crate::threading::JoinError;
+use crate::timer;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Joins a thread that returns a value and one that panics.
+pub fn join_threads() {
+    let answer = threading::spawn("selftest-join", || 6 * 7);
+    assert_eq!(answer.join().unwrap(), 42);
+
+    let panicking = threading::spawn("selftest-panic", || -> u64 { panic!("on purpose") });
+    match panicking.join() {
+        Err(JoinError::Panicked(message)) => assert!(message.contains("on purpose")),
+        Ok(_) => panic!("Panicking thread returned a value"),
+    }
+}
+
+/// Runs hundreds of short threads and makes sure the reaper gives all of their stacks back.
+pub fn reap_threads() {
+    const THREADS: usize = 300;
+    const BATCH: usize = 50;
+
+    let stacks_used = || without_interrupts(|| memory::stats().kernel_stacks.used);
+    let before = stacks_used();
+
+    for _ in 0..THREADS / BATCH {
+        let threads = (0..BATCH)
+            .map(|index| threading::spawn("selftest-reap", move || index))
+            .collect::<Vec<_>>();
+        assert!(
+            join_all(threads).into_iter().eq(0..BATCH),
+            "Threads returned the wrong values"
+        );
+    }
+
+    // The reaper frees threads whenever it gets to run, give it some time to catch up.
+    for _ in 0..100 {
+        if stacks_used() <= before {
+            break;
+        }
+        threading::sleep(Duration::from_millis(10));
+    }
+    assert!(stacks_used() <= before, "Thread stacks leaked");
+}
+
+/// Sleeps while nothing else wants to run, so the time has to be spent in the idle thread.
+pub fn sleep() {
+    let start = timer::ticks();
+    let idle_start = scheduler::idle_ticks();
+
+    threading::sleep(Duration::from_millis(50));
+
+    assert!(timer::ticks() - start >= 5, "Woke up too early");
+    assert!(
+        scheduler::idle_ticks() > idle_start,
+        "Idle thread didn't run"
+    );
+}
+
+/// Counts how often it was added to, and how many of them were dropped.
+struct Tally(Cell<u64>);
+
+static DROPPED_TALLIES: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        DROPPED_TALLIES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+bmos_std::thread_local! {
+    static TALLY: Tally = Tally(Cell::new(0));
+}
+
+/// Lets several threads add to their own thread-local tally, giving up the CPU in between, and
+/// makes sure the tallies are dropped when the threads end.
+pub fn thread_locals() {
+    const ROUNDS: u64 = 50;
+
+    let dropped = DROPPED_TALLIES.load(Ordering::Relaxed);
+    join_all(spawn_contenders("selftest-tls", |index| {
+        let increment = index as u64 + 1;
+        for _ in 0..ROUNDS {
+            TALLY.with(|tally| tally.0.set(tally.0.get() + increment));
+            threading::yield_now();
+        }
+
+        let total = TALLY.with(|tally| tally.0.get());
+        assert_eq!(
+            total,
+            increment * ROUNDS,
+            "Threads share a thread-local value"
+        );
+    }));
+
+    assert_eq!(
+        DROPPED_TALLIES.load(Ordering::Relaxed) - dropped,
+        CONTENDERS,
+        "Thread-local values weren't dropped"
+    );
+}
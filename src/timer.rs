@@ -0,0 +1,71 @@
+use crate::scheduler;
+use crate::threading::ThreadId;
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// How often the timer interrupt fires.
+pub const TICKS_PER_SECOND: u64 = 100;
+
+/// The PIT's input clock in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Sleeping threads, ordered by the tick they want to be woken up at.
+    /// Only touched with interrupts disabled, since the timer interrupt handler pops from it.
+    static ref SLEEPERS: Mutex<BinaryHeap<Reverse<(u64, ThreadId)>>> = Mutex::new(BinaryHeap::new());
+}
+
+/// Programs the PIT to fire the timer interrupt `TICKS_PER_SECOND` times a second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+
+    unsafe {
+        // Channel 0, low byte then high byte, square wave generator
+        command.write(0x36);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Ticks since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts `duration` to ticks, rounding up so nobody wakes up too early.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / TICKS_PER_SECOND as u128;
+    let ticks = (duration.as_nanos() + nanos_per_tick - 1) / nanos_per_tick;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Makes sure the thread `id` is woken up once the timer reaches `tick`. The thread has to
+/// block itself afterwards, with interrupts still disabled.
+pub fn wake_at(tick: u64, id: ThreadId) {
+    SLEEPERS.lock().push(Reverse((tick, id)));
+}
+
+/// Called by the timer interrupt handler. Wakes every thread whose time has come.
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(Reverse((tick, id))) = sleepers.peek().copied() {
+        if tick > now {
+            break;
+        }
+
+        sleepers.pop();
+        scheduler::wake(id);
+    }
+}